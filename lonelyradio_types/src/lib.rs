@@ -1,8 +1,20 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::io::{Read, Write};
 
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+// Legacy hello, protocol version 0
 pub const HELLO_MAGIC: &[u8; 8] = b"lonelyra";
+// Followed by the client's PROTOCOL_VERSION (u16, little endian)
+pub const HELLO_MAGIC_VERSIONED: &[u8; 8] = b"lonelyrv";
+// 1 - ServerCapabilities lists requests and features
+// 2 - PlayMessage::N (notices)
+pub const PROTOCOL_VERSION: u16 = 2;
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum Request {
@@ -13,6 +25,123 @@ pub enum Request {
 	ListPlaylist,
 	#[serde(rename = "ppl", alias = "PlayPlaylist")]
 	PlayPlaylist(String, Settings),
+	#[serde(rename = "b", alias = "Browse")]
+	Browse(Browse, Page),
+	// Full-text search over title, artist and album
+	#[serde(rename = "s", alias = "Search")]
+	Search(String, Page),
+	// Following requests fall back to the whole library shuffle, when the
+	// selection is exhausted
+	#[serde(rename = "pt", alias = "PlayTrack")]
+	PlayTrack(u64, Settings),
+	// Plays album (artist, album) in order
+	#[serde(rename = "pal", alias = "PlayAlbum")]
+	PlayAlbum(String, String, Settings),
+	// Shuffles all tracks of the artist
+	#[serde(rename = "par", alias = "PlayArtist")]
+	PlayArtist(String, Settings),
+	// Proof of the token (see auth_proof()), sent before the actual request.
+	// Responded with RequestResult::Ok or Unauthorized
+	#[serde(rename = "au", alias = "Authenticate")]
+	Authenticate(#[serde(with = "serde_bytes")] Vec<u8>),
+	// Listeners and recently played tracks, without starting playback
+	#[serde(rename = "sta", alias = "Status")]
+	Status,
+}
+
+impl Request {
+	pub fn kind(&self) -> RequestKind {
+		match self {
+			Request::Play(_) => RequestKind::Play,
+			Request::ListPlaylist => RequestKind::ListPlaylist,
+			Request::PlayPlaylist(_, _) => RequestKind::PlayPlaylist,
+			Request::Browse(_, _) => RequestKind::Browse,
+			Request::Search(_, _) => RequestKind::Search,
+			Request::PlayTrack(_, _) => RequestKind::PlayTrack,
+			Request::PlayAlbum(_, _, _) => RequestKind::PlayAlbum,
+			Request::PlayArtist(_, _) => RequestKind::PlayArtist,
+			Request::Authenticate(_) => RequestKind::Authenticate,
+			Request::Status => RequestKind::Status,
+		}
+	}
+}
+
+// Request without its arguments, advertised in ServerCapabilities
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum RequestKind {
+	#[serde(rename = "p")]
+	Play,
+	#[serde(rename = "lpl")]
+	ListPlaylist,
+	#[serde(rename = "ppl")]
+	PlayPlaylist,
+	#[serde(rename = "b")]
+	Browse,
+	#[serde(rename = "s")]
+	Search,
+	#[serde(rename = "pt")]
+	PlayTrack,
+	#[serde(rename = "pal")]
+	PlayAlbum,
+	#[serde(rename = "par")]
+	PlayArtist,
+	#[serde(rename = "au")]
+	Authenticate,
+	#[serde(rename = "sta")]
+	Status,
+	// Request from a newer protocol version
+	#[serde(other)]
+	Unknown,
+}
+
+// Optional features, advertised in ServerCapabilities
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+	// Control messages are accepted during playback
+	#[serde(rename = "cc")]
+	Control,
+	// Covers are sent in TrackMetadata (JPEG)
+	#[serde(rename = "co")]
+	Cover,
+	// Framed PlayMessages and fragments (see Settings.framing)
+	#[serde(rename = "fr")]
+	Framing,
+	// Some stations are encrypted (see Settings.encryption)
+	#[serde(rename = "en")]
+	Encryption,
+	// Feature from a newer protocol version
+	#[serde(other)]
+	Unknown,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Browse {
+	#[serde(rename = "ar", alias = "Artists")]
+	Artists,
+	// Albums of an artist
+	#[serde(rename = "al", alias = "Albums")]
+	Albums(String),
+	// Tracks of an album (artist, album)
+	#[serde(rename = "tr", alias = "Tracks")]
+	Tracks(String, String),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Page {
+	#[serde(rename = "o")]
+	pub offset: u32,
+	// Server may return less
+	#[serde(rename = "l")]
+	pub limit: u32,
+}
+
+impl Default for Page {
+	fn default() -> Self {
+		Self {
+			offset: 0,
+			limit: 100,
+		}
+	}
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -20,11 +149,87 @@ pub struct PlaylistResponce {
 	pub playlists: Vec<String>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LibraryResponce {
+	#[serde(rename = "i")]
+	pub items: Vec<LibraryItem>,
+	// Number of items without pagination
+	#[serde(rename = "t")]
+	pub total: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum LibraryItem {
+	#[serde(rename = "ar")]
+	Artist(String),
+	#[serde(rename = "al")]
+	Album(String),
+	#[serde(rename = "tr")]
+	Track(LibraryTrack),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LibraryTrack {
+	// Stable across server restarts and rescans
+	#[serde(rename = "id")]
+	pub id: u64,
+	#[serde(rename = "mt")]
+	pub title: String,
+	#[serde(rename = "mal")]
+	pub album: String,
+	#[serde(rename = "mar")]
+	pub artist: String,
+	#[serde(rename = "tn", skip_serializing_if = "Option::is_none", default)]
+	pub track_number: Option<u32>,
+	#[serde(rename = "y", skip_serializing_if = "Option::is_none", default)]
+	pub year: Option<u32>,
+}
+
+// Only stations available to the client are included
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct StatusResponce {
+	// Seconds since the server started
+	#[serde(rename = "u")]
+	pub uptime: u64,
+	// Station ("" for the whole library) -> listeners
+	#[serde(rename = "l")]
+	pub listeners: BTreeMap<String, u64>,
+	// Every listener gets its own shuffle, so there is no current track of a
+	// station. Tracks recently started by listeners, newest first
+	#[serde(rename = "r")]
+	pub recent: Vec<RecentTrack>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RecentTrack {
+	#[serde(rename = "st")]
+	pub station: String,
+	// Same as LibraryTrack.id
+	#[serde(rename = "id")]
+	pub id: u64,
+	#[serde(rename = "mt")]
+	pub title: String,
+	#[serde(rename = "mal")]
+	pub album: String,
+	#[serde(rename = "mar")]
+	pub artist: String,
+	// Seconds since the track was started
+	#[serde(rename = "a")]
+	pub ago: u64,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum RequestResult {
 	Ok,
 	Playlist(PlaylistResponce),
 	Error(RequestError),
+	Library(LibraryResponce),
+	// Ok, but the stream is encrypted with the station's key and this salt
+	// (see Cipher)
+	#[serde(rename = "se")]
+	Sealed(#[serde(with = "serde_bytes")] Vec<u8>),
+	#[serde(rename = "sta")]
+	Status(StatusResponce),
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -32,12 +237,75 @@ pub enum RequestError {
 	NoSuchPlaylist,
 	WrongCoverSize,
 	UnsupportedEncoder,
+	NoSuchTrack,
+	NoSuchAlbum,
+	NoSuchArtist,
+	// Missing, wrong or expired token
+	Unauthorized,
+	// Station is encrypted, but the client didn't set Settings.encryption
+	EncryptionRequired,
+	// Listener limits of the server are reached, try again later
+	ServerBusy,
+}
+
+// Sent by the client during playback, if Feature::Control is advertised.
+// The server acts on it at the next fragment boundary
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Control {
+	// Start the next track
+	#[serde(rename = "sk", alias = "Skip")]
+	Skip,
+	// Start the current track from the beginning
+	#[serde(rename = "rp", alias = "Replay")]
+	Replay,
+	// Continue the current track with another encoder
+	#[serde(rename = "ce", alias = "ChangeEncoder")]
+	ChangeEncoder(Encoder),
+	// Start the next track from another playlist ("" for the whole library)
+	#[serde(rename = "cp", alias = "ChangePlaylist")]
+	ChangePlaylist(String),
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum PlayMessage {
 	T(TrackMetadata),
 	F(FragmentMetadata),
+	// Only sent if negotiated protocol version is 2 or newer
+	N(Notice),
+}
+
+// Out-of-band message from the server, may arrive between any fragments
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum Notice {
+	// Text for the listener
+	#[serde(rename = "a")]
+	Announcement(String),
+	// Playing another playlist ("" for the whole library) from now on
+	#[serde(rename = "st")]
+	Station(String),
+	// Server is going down, connection will be closed
+	#[serde(rename = "sd")]
+	Shutdown {
+		// Seconds to wait before reconnecting, if server will be back
+		#[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+		reconnect: Option<u32>,
+	},
+}
+
+impl Display for Notice {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Announcement(text) => f.write_str(text),
+			Self::Station(playlist) if playlist.is_empty() => f.write_str("Playing all tracks"),
+			Self::Station(playlist) => write!(f, "Playing \"{}\"", playlist),
+			Self::Shutdown {
+				reconnect: Some(seconds),
+			} => write!(f, "Server is shutting down, reconnect in {} s", seconds),
+			Self::Shutdown {
+				reconnect: None,
+			} => f.write_str("Server is shutting down"),
+		}
+	}
 }
 
 #[repr(C)]
@@ -48,15 +316,187 @@ pub struct Settings {
 
 	#[serde(rename = "co", alias = "cover")]
 	pub cover: i32,
+
+	// Only if Feature::Framing is advertised
+	#[serde(rename = "fr", default, skip_serializing_if = "Option::is_none")]
+	pub framing: Option<Framing>,
+
+	// Client has a key for encrypted stations, only if Feature::Encryption is
+	// advertised
+	#[serde(rename = "en", default, skip_serializing_if = "is_false")]
+	pub encryption: bool,
+}
+
+// Limits requested by the client. PlayMessages and fragments are sent as frames
+// (see write_frame()) and never exceed them
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Framing {
+	// Serialized PlayMessage, covers are dropped if they don't fit
+	#[serde(rename = "mm")]
+	pub max_message: u32,
+	// Encoded fragment
+	#[serde(rename = "mf")]
+	pub max_fragment: u32,
+}
+
+// Smaller limits are raised to it, so every fragment could fit
+pub const MIN_FRAME: u32 = 64 * 1024;
+
+impl Framing {
+	// Limits the server actually uses
+	pub fn effective(self) -> Self {
+		Self {
+			max_message: self.max_message.max(MIN_FRAME),
+			max_fragment: self.max_fragment.max(MIN_FRAME),
+		}
+	}
+}
+
+// Frame is payload length (u32 LE), CRC-32 of the payload (u32 LE) and the payload.
+// Written at once, so message-based transports carry it in one message
+pub fn write_frame(mut w: impl Write, payload: &[u8]) -> std::io::Result<()> {
+	let mut frame = Vec::with_capacity(payload.len() + 8);
+	frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+	frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+	frame.extend_from_slice(payload);
+	w.write_all(&frame)
+}
+
+// Reads a frame, rejecting payloads longer than `max` before allocating them
+pub fn read_frame(mut r: impl Read, max: u32) -> std::io::Result<Vec<u8>> {
+	let mut header = [0u8; 8];
+	r.read_exact(&mut header)?;
+	let length = u32::from_le_bytes(header[..4].try_into().unwrap());
+	let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+	if length > max {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("frame of {} bytes exceeds the limit of {} bytes", length, max),
+		));
+	}
+	let mut payload = vec![0u8; length as usize];
+	r.read_exact(&mut payload)?;
+	if crc32fast::hash(&payload) != checksum {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"frame checksum mismatch",
+		));
+	}
+	Ok(payload)
+}
+
+// Added to the frame by Cipher::seal()
+pub const SEAL_OVERHEAD: u32 = 16;
+
+// ChaCha20-Poly1305 for frames of encrypted streams. Key is SHA-256 of the key
+// file and the salt, nonce is the number of the frame
+pub struct Cipher {
+	cipher: ChaCha20Poly1305,
+	counter: u64,
+}
+
+impl Cipher {
+	pub fn new(key: &[u8], salt: &[u8]) -> Self {
+		let key = Sha256::new().chain_update(key).chain_update(salt).finalize();
+		Self {
+			cipher: chacha20poly1305::KeyInit::new(&key),
+			counter: 0,
+		}
+	}
+
+	fn nonce(&mut self) -> Nonce {
+		let mut nonce = Nonce::default();
+		nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+		self.counter += 1;
+		nonce
+	}
+
+	pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+		let nonce = self.nonce();
+		self.cipher.encrypt(&nonce, payload).unwrap()
+	}
+
+	// None if the key is wrong or the frame was tampered with
+	pub fn open(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+		let nonce = self.nonce();
+		self.cipher.decrypt(&nonce, payload).ok()
+	}
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct ServerCapabilities {
+	// Encoders that can't fit into max_bandwidth aren't listed
 	#[serde(rename = "e")]
 	pub encoders: Vec<Encoder>,
-	// Will be used in the next updates
-	//#[serde(rename = "ar")]
-	//pub available_requests: Vec<Request>,
+	// Bandwidth ceiling for every listener in kbit/s, if the server has one
+	#[serde(rename = "bw", default, skip_serializing_if = "Option::is_none")]
+	pub max_bandwidth: Option<u32>,
+	// Negotiated protocol version (minimum of the client's and the server's), 0
+	// for older servers and legacy hello
+	#[serde(rename = "v", default)]
+	pub version: u16,
+	#[serde(rename = "ar", default = "legacy_requests")]
+	pub available_requests: Vec<RequestKind>,
+	#[serde(rename = "f", default)]
+	pub features: Vec<Feature>,
+	#[serde(rename = "st", default, skip_serializing_if = "Option::is_none")]
+	pub station: Option<StationInfo>,
+	// Random bytes for Request::Authenticate, only if some stations are private
+	#[serde(
+		rename = "ch",
+		skip_serializing_if = "Option::is_none",
+		with = "serde_bytes",
+		default = "none"
+	)]
+	pub challenge: Option<Vec<u8>>,
+}
+
+// HMAC-SHA256 of the server's challenge with the token as a key, so the token
+// itself is never sent
+pub fn auth_proof(token: &str, challenge: &[u8]) -> Vec<u8> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+	mac.update(challenge);
+	mac.finalize().into_bytes().to_vec()
+}
+
+// Constant-time check of auth_proof()
+pub fn verify_proof(token: &str, challenge: &[u8], proof: &[u8]) -> bool {
+	let mut mac = Hmac::<Sha256>::new_from_slice(token.as_bytes()).unwrap();
+	mac.update(challenge);
+	mac.verify_slice(proof).is_ok()
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct StationInfo {
+	#[serde(rename = "n")]
+	pub name: String,
+	#[serde(rename = "d", default, skip_serializing_if = "String::is_empty")]
+	pub description: String,
+	#[serde(rename = "h", default, skip_serializing_if = "Option::is_none")]
+	pub homepage: Option<String>,
+	// JPEG, no larger than 256x256
+	#[serde(
+		rename = "i",
+		skip_serializing_if = "Option::is_none",
+		with = "serde_bytes",
+		default = "none"
+	)]
+	pub icon: Option<Vec<u8>>,
+}
+
+impl ServerCapabilities {
+	pub fn supports(&self, request: RequestKind) -> bool {
+		self.available_requests.contains(&request)
+	}
+
+	pub fn has(&self, feature: Feature) -> bool {
+		self.features.contains(&feature)
+	}
+}
+
+// Requests of servers, which do not advertise them
+fn legacy_requests() -> Vec<RequestKind> {
+	vec![RequestKind::Play, RequestKind::ListPlaylist, RequestKind::PlayPlaylist]
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
@@ -86,7 +526,12 @@ pub struct TrackMetadata {
 	)]
 	pub cover: Option<Vec<u8>>,
 
+	// Lowest byte of track_id, kept for older clients
 	pub id: u8,
+
+	// Stable across server restarts and rescans, same as LibraryTrack.id
+	#[serde(rename = "tid", default)]
+	pub track_id: u64,
 }
 
 // WavPack, Opus and Aac are currently unimplemented.
@@ -117,12 +562,24 @@ pub struct FragmentMetadata {
 		default = "none"
 	)]
 	pub magic_cookie: Option<Vec<u8>>,
+
+	// Position of the first frame (samples per channel) in the track
+	#[serde(rename = "of", default, skip_serializing_if = "Option::is_none")]
+	pub offset: Option<u64>,
+
+	// Number of the fragment in the connection, to detect lost ones
+	#[serde(rename = "sq", default, skip_serializing_if = "Option::is_none")]
+	pub sequence: Option<u64>,
 }
 
 fn none() -> Option<Vec<u8>> {
 	None
 }
 
+fn is_false(x: &bool) -> bool {
+	!x
+}
+
 impl Display for Encoder {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		f.write_str(match self {
@@ -138,3 +595,199 @@ impl Display for Encoder {
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Cursor, ErrorKind};
+
+	use super::*;
+
+	fn frame(payload: &[u8]) -> Vec<u8> {
+		let mut frame = vec![];
+		write_frame(&mut frame, payload).unwrap();
+		frame
+	}
+
+	#[test]
+	fn proofs() {
+		let proof = auth_proof("token", b"challenge");
+		assert_eq!(proof.len(), 32);
+		assert!(verify_proof("token", b"challenge", &proof));
+		assert!(!verify_proof("other", b"challenge", &proof));
+		assert!(!verify_proof("token", b"another challenge", &proof));
+		assert!(!verify_proof("token", b"challenge", &proof[..16]));
+		// Token itself doesn't prove anything
+		assert!(!verify_proof("token", b"challenge", b"token"));
+	}
+
+	#[test]
+	fn notices() {
+		for (notice, text) in [
+			(Notice::Announcement("Back soon".to_string()), "Back soon"),
+			(Notice::Station(String::new()), "Playing all tracks"),
+			(Notice::Station("jazz".to_string()), "Playing \"jazz\""),
+			(
+				Notice::Shutdown {
+					reconnect: Some(30),
+				},
+				"Server is shutting down, reconnect in 30 s",
+			),
+			(
+				Notice::Shutdown {
+					reconnect: None,
+				},
+				"Server is shutting down",
+			),
+		] {
+			assert_eq!(notice.to_string(), text);
+			let message = rmp_serde::to_vec_named(&PlayMessage::N(notice.clone())).unwrap();
+			assert_eq!(
+				rmp_serde::from_slice::<PlayMessage>(&message).unwrap(),
+				PlayMessage::N(notice)
+			);
+		}
+		// Unknown reconnect time isn't sent at all
+		let shutdown = rmp_serde::to_vec_named(&Notice::Shutdown {
+			reconnect: None,
+		})
+		.unwrap();
+		assert_eq!(
+			shutdown,
+			rmp_serde::to_vec_named(&BTreeMap::from([("sd", BTreeMap::<&str, u32>::new())]))
+				.unwrap()
+		);
+	}
+
+	#[test]
+	fn legacy_capabilities() {
+		// All servers predating versioning sent
+		#[derive(Serialize)]
+		struct Legacy {
+			e: Vec<Encoder>,
+		}
+		let legacy = rmp_serde::to_vec_named(&Legacy {
+			e: vec![Encoder::Pcm16, Encoder::Flac],
+		})
+		.unwrap();
+		let capabilities: ServerCapabilities = rmp_serde::from_slice(&legacy).unwrap();
+		assert_eq!(capabilities.version, 0);
+		assert_eq!(capabilities.encoders, vec![Encoder::Pcm16, Encoder::Flac]);
+		assert_eq!(capabilities.available_requests, legacy_requests());
+		assert!(capabilities.features.is_empty());
+		assert_eq!(capabilities.challenge, None);
+		assert_eq!(capabilities.station, None);
+	}
+
+	#[test]
+	fn newer_capabilities() {
+		#[derive(Serialize)]
+		struct Newer {
+			e: Vec<Encoder>,
+			v: u16,
+			ar: Vec<&'static str>,
+			f: Vec<&'static str>,
+			future: u8,
+		}
+		let newer = rmp_serde::to_vec_named(&Newer {
+			e: vec![Encoder::Pcm16],
+			v: PROTOCOL_VERSION + 1,
+			ar: vec!["p", "future"],
+			f: vec!["cc", "future"],
+			future: 1,
+		})
+		.unwrap();
+		let capabilities: ServerCapabilities = rmp_serde::from_slice(&newer).unwrap();
+		assert!(capabilities.supports(RequestKind::Play));
+		assert!(!capabilities.supports(RequestKind::ListPlaylist));
+		assert!(capabilities.has(Feature::Control));
+		assert_eq!(capabilities.available_requests, vec![RequestKind::Play, RequestKind::Unknown]);
+		assert_eq!(capabilities.features, vec![Feature::Control, Feature::Unknown]);
+	}
+
+	#[test]
+	fn frame_roundtrip() {
+		let mut stream = Cursor::new([frame(b"hello"), frame(b"")].concat());
+		assert_eq!(read_frame(&mut stream, 5).unwrap(), b"hello");
+		assert_eq!(read_frame(&mut stream, 5).unwrap(), b"");
+		assert_eq!(read_frame(&mut stream, 5).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+	}
+
+	#[test]
+	fn small_limits_are_raised() {
+		let framing = Framing {
+			max_message: 1,
+			max_fragment: MIN_FRAME + 1,
+		};
+		assert_eq!(
+			framing.effective(),
+			Framing {
+				max_message: MIN_FRAME,
+				max_fragment: MIN_FRAME + 1,
+			}
+		);
+	}
+
+	#[test]
+	fn truncated_frame() {
+		let frame = frame(b"hello");
+		for length in [3, 8, frame.len() - 1] {
+			let error = read_frame(&frame[..length], 5).unwrap_err();
+			assert_eq!(error.kind(), ErrorKind::UnexpectedEof, "{}", length);
+		}
+	}
+
+	#[test]
+	fn oversized_frame() {
+		let error = read_frame(&frame(b"hello")[..], 4).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+		// Rejected from the header alone, the payload is never read
+		let header = [u32::MAX.to_le_bytes(), 0u32.to_le_bytes()].concat();
+		let error = read_frame(&header[..], MIN_FRAME).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn checksum_mismatch() {
+		let mut frame = frame(b"hello");
+		*frame.last_mut().unwrap() ^= 1;
+		let error = read_frame(&frame[..], 5).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+		assert_eq!(error.to_string(), "frame checksum mismatch");
+	}
+
+	#[test]
+	fn cipher_roundtrip() {
+		let mut sealer = Cipher::new(b"key", b"salt");
+		let mut opener = Cipher::new(b"key", b"salt");
+		for payload in [&b"first"[..], b"", b"third"] {
+			let sealed = sealer.seal(payload);
+			assert_eq!(sealed.len(), payload.len() + SEAL_OVERHEAD as usize);
+			assert_eq!(opener.open(&sealed).unwrap(), payload);
+		}
+	}
+
+	#[test]
+	fn cipher_nonce_counter() {
+		let mut sealer = Cipher::new(b"key", b"salt");
+		let first = sealer.seal(b"same");
+		let second = sealer.seal(b"same");
+		// Every frame has its own nonce
+		assert_ne!(first, second);
+		// Skipped, reordered or replayed frames are rejected
+		let mut opener = Cipher::new(b"key", b"salt");
+		assert_eq!(opener.open(&second), None);
+		let mut opener = Cipher::new(b"key", b"salt");
+		assert!(opener.open(&first).is_some());
+		assert_eq!(opener.open(&first), None);
+	}
+
+	#[test]
+	fn cipher_rejects_wrong_key_and_tampering() {
+		let sealed = Cipher::new(b"key", b"salt").seal(b"hello");
+		assert_eq!(Cipher::new(b"other", b"salt").open(&sealed), None);
+		assert_eq!(Cipher::new(b"key", b"other").open(&sealed), None);
+		let mut tampered = sealed.clone();
+		tampered[0] ^= 1;
+		assert_eq!(Cipher::new(b"key", b"salt").open(&tampered), None);
+	}
+}
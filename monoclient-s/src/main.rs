@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use monolib::lonelyradio_types;
 use monolib::State;
@@ -8,24 +8,50 @@ use slint::{
 
 slint::include_modules!();
 
+const NOTICE_DURATION: Duration = Duration::from_secs(10);
+
+fn decode_jpeg(jpeg: Vec<u8>) -> Option<SharedPixelBuffer<Rgb8Pixel>> {
+	let mut decoder = zune_jpeg::JpegDecoder::new(jpeg);
+	decoder.decode_headers().ok()?;
+	let (w, h) = decoder.dimensions()?;
+	let decoded = decoder.decode().ok()?;
+	let mut pixel_buffer = SharedPixelBuffer::<Rgb8Pixel>::new(w as u32, h as u32);
+	pixel_buffer.make_mut_bytes().copy_from_slice(&decoded);
+	Some(pixel_buffer)
+}
+
 #[allow(dead_code)]
 fn start_playback(window_weak: Weak<MainWindow>) {
 	let window = window_weak.upgrade().unwrap();
 	let addr = window.get_addr().to_string();
 	let playlist = window.get_selected_playlist();
 	let encoder = monolib::SUPPORTED_DECODERS[window.get_selected_encoder() as usize];
+	let key_file = window.get_key_file().to_string();
+	monolib::set_key(match key_file.as_str() {
+		"" => None,
+		path => match std::fs::read(path) {
+			Ok(key) => Some(key),
+			Err(e) => {
+				eprintln!("Failed to read key file {}: {}", path, e);
+				None
+			}
+		},
+	});
 	let handle = std::thread::spawn(move || {
 		monolib::run(
 			&addr,
 			lonelyradio_types::Settings {
 				encoder,
 				cover: 2048,
+				framing: None,
+				encryption: false,
 			},
 			if playlist == "All tracks" {
 				""
 			} else {
 				&playlist
 			},
+			None,
 		)
 	});
 	std::thread::sleep(Duration::from_millis(166));
@@ -71,7 +97,7 @@ pub fn main() {
 		}
 		let window = window_weak.upgrade().unwrap();
 
-		let playlists = match monolib::list_playlists(&window.get_addr()) {
+		let playlists = match monolib::list_playlists(&window.get_addr(), None) {
 			Some(v) => [vec!["All tracks".to_string()], v].concat(),
 			None => vec!["All tracks".to_string()],
 		};
@@ -90,8 +116,10 @@ pub fn main() {
 
 	let window_weak = window.as_weak();
 	window.on_next(move || {
-		monolib::stop();
-		start_playback(window_weak.clone())
+		if !monolib::next() {
+			monolib::stop();
+			start_playback(window_weak.clone())
+		}
 	});
 	let window_weak = window.as_weak();
 	window.on_stop(move || {
@@ -100,6 +128,8 @@ pub fn main() {
 		window.set_martist("".into());
 		window.set_malbum("".into());
 		window.set_mtitle("".into());
+		window.set_station("".into());
+		window.set_station_info("".into());
 		window.set_cover(Image::from_rgba8(SharedPixelBuffer::<Rgba8Pixel>::new(1, 1)));
 		monolib::stop();
 	});
@@ -113,13 +143,10 @@ pub fn main() {
 		}
 		let md = monolib::get_metadata().unwrap();
 		let _md = md.clone();
-		if let Some(jpeg) = md.cover {
-			let mut decoder = zune_jpeg::JpegDecoder::new(jpeg);
-			decoder.decode_headers().unwrap();
-			let (w, h) = decoder.dimensions().unwrap();
-			let decoded = decoder.decode().unwrap();
-			let mut pixel_buffer = SharedPixelBuffer::<Rgb8Pixel>::new(w as u32, h as u32);
-			pixel_buffer.make_mut_bytes().copy_from_slice(&decoded);
+		let station = monolib::get_station();
+		// Station icon stands in for missing covers
+		let cover = md.cover.or_else(|| station.as_ref().and_then(|x| x.icon.clone()));
+		if let Some(pixel_buffer) = cover.and_then(decode_jpeg) {
 			window
 				.upgrade_in_event_loop(|win| {
 					let image = Image::from_rgb8(pixel_buffer);
@@ -135,6 +162,24 @@ pub fn main() {
 		}
 		slint::invoke_from_event_loop(move || {
 			let window = window.unwrap();
+			match station {
+				Some(station) => {
+					window.set_station(station.name.into());
+					window.set_station_info(
+						[Some(station.description), station.homepage]
+							.into_iter()
+							.flatten()
+							.filter(|x| !x.is_empty())
+							.collect::<Vec<String>>()
+							.join(" - ")
+							.into(),
+					);
+				}
+				None => {
+					window.set_station("".into());
+					window.set_station_info("".into());
+				}
+			}
 			window.set_martist(md.artist.clone().into());
 			window.set_malbum(md.album.clone().into());
 			window.set_mtitle(md.title.clone().into());
@@ -144,5 +189,22 @@ pub fn main() {
 			std::thread::sleep(Duration::from_millis(100))
 		}
 	});
+
+	// Notices are shown for a while under the track info
+	let window_weak = window.as_weak();
+	std::thread::spawn(move || {
+		let mut shown: Option<Instant> = None;
+		loop {
+			if let Some(notice) = monolib::take_notice() {
+				shown = Some(Instant::now());
+				let text = SharedString::from(notice.to_string());
+				window_weak.upgrade_in_event_loop(move |win| win.set_notice(text)).unwrap();
+			} else if shown.is_some_and(|x| x.elapsed() > NOTICE_DURATION) {
+				shown = None;
+				window_weak.upgrade_in_event_loop(|win| win.set_notice("".into())).unwrap();
+			}
+			std::thread::sleep(Duration::from_millis(250))
+		}
+	});
 	window.run().unwrap();
 }
@@ -237,7 +237,7 @@ struct Player: View {
 				var id = -1
 				playlists =
 					(["All tracks"]
-					+ String(cString: c_list_playlists(server)).components(separatedBy: "\n")).map({
+					+ String(cString: c_list_playlists(server, nil)).components(separatedBy: "\n")).map({
 						elem in
 						if elem.isEmpty {
 							return PlayList(id: -1, name: elem)
@@ -304,7 +304,7 @@ struct Player: View {
 						server,
 						CSettings(
 							encoder: settings.encoder.rawValue, cover: settings.cover_size.rawValue),
-						playlist.name == "All tracks" ? "" : playlist.name)
+						playlist.name == "All tracks" ? "" : playlist.name, nil)
 				}
 			}
 		default:
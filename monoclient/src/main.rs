@@ -4,9 +4,10 @@ use crossterm::event::{poll, read, Event};
 use crossterm::style::Print;
 use crossterm::terminal::{Clear, ClearType};
 use monolib::lonelyradio_types::{Encoder, Settings};
+use monolib::TlsOptions;
 use std::io::stdout;
+use std::path::PathBuf;
 use std::sync::OnceLock;
-use std::time::Instant;
 
 static VERBOSE: OnceLock<bool> = OnceLock::new();
 
@@ -23,13 +24,43 @@ struct Args {
 
 	#[arg(short, long)]
 	list: bool,
+
+	/// Show listeners and recently played tracks instead of playing
+	#[arg(long)]
+	status: bool,
+
+	/// Token for private stations
+	#[arg(long)]
+	token: Option<String>,
+
+	/// Additional trusted CA certificates (PEM) for lonelyradios:// servers
+	#[arg(long)]
+	ca: Option<PathBuf>,
+
+	/// SHA-256 of the server certificate to accept instead of checking CA
+	#[arg(long, value_parser = parse_pin)]
+	pin: Option<[u8; 32]>,
+
+	/// Key file for encrypted stations
+	#[arg(long)]
+	key_file: Option<PathBuf>,
+}
+
+fn format_duration(seconds: u64) -> String {
+	format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], &'static str> {
+	monolib::parse_pin(pin).ok_or("expected 32 bytes in hex")
 }
 
 const HELP: &str = r#"Keybinds:
-  Up   - Volume up
-  Down - Volume down
-  Q    - Quit monoclient
-  H    - Show this help"#;
+  Up    - Volume up
+  Down  - Volume down
+  Space - Play/pause
+  N     - Next track
+  Q     - Quit monoclient
+  H     - Show this help"#;
 
 macro_rules! verbose {
     ($($arg:tt)*) => {{
@@ -43,32 +74,72 @@ macro_rules! verbose {
 fn main() {
 	let args = Args::parse();
 	VERBOSE.set(args.verbose).unwrap();
+	monolib::set_tls_options(TlsOptions {
+		ca: args.ca.as_ref().map(|x| std::fs::read(x).unwrap()),
+		pin: args.pin,
+	})
+	.unwrap();
+	monolib::set_key(args.key_file.as_ref().map(|x| std::fs::read(x).unwrap()));
 	if args.list {
 		println!(
 			"Available playlists: {}",
-			match monolib::list_playlists(&args.address) {
+			match monolib::list_playlists(&args.address, args.token.as_deref()) {
 				Some(s) => format!("{:?}", s),
 				None => String::from("None"),
 			}
 		);
 		return;
 	}
+	if args.status {
+		match monolib::status(&args.address, args.token.as_deref()) {
+			Ok(status) => {
+				println!("Uptime: {}", format_duration(status.uptime));
+				println!("Listeners:");
+				for (station, listeners) in &status.listeners {
+					println!(
+						"  {}: {}",
+						if station.is_empty() {
+							"All tracks"
+						} else {
+							station
+						},
+						listeners
+					);
+				}
+				println!("Recently played:");
+				for track in &status.recent {
+					println!(
+						"  {} - {} - {} ({} ago)",
+						track.artist,
+						track.album,
+						track.title,
+						format_duration(track.ago)
+					);
+				}
+			}
+			Err(e) => eprintln!("Failed to get status: {}", e),
+		}
+		return;
+	}
 	std::thread::spawn(move || {
 		monolib::run(
 			&args.address,
 			Settings {
 				encoder: Encoder::Sea,
 				cover: -1,
+				framing: None,
+				encryption: false,
 			},
 			&args.playlist,
+			args.token.as_deref(),
 		)
 	});
 	while monolib::get_metadata().is_none() {}
 	let mut md = monolib::get_metadata().unwrap();
-	let mut next_md = md.clone();
 	verbose!("md: {:?}", md);
-	let mut track_start = Instant::now();
 	let mut seconds_past = 0;
+	// Lost fragments and frames in gaps already reported
+	let mut losses = (0, 0);
 	crossterm::execute!(
 		stdout(),
 		Print(format!(
@@ -111,7 +182,7 @@ fn main() {
 						.unwrap();
 						println!("{}", HELP);
 						crossterm::terminal::enable_raw_mode().unwrap();
-						seconds_past = (Instant::now() - track_start).as_secs();
+						seconds_past = monolib::get_position().unwrap_or_default() as u64;
 						crossterm::execute!(
 							stdout(),
 							Print(format!(
@@ -129,31 +200,41 @@ fn main() {
 						.unwrap();
 					}
 					(crossterm::event::KeyCode::Char(' '), _) => monolib::toggle(),
+					(crossterm::event::KeyCode::Char('n' | 'т'), _) => {
+						if monolib::next() {
+							verbose!("Skipping");
+						} else {
+							verbose!("Server does not support skipping");
+						}
+					}
 					_ => {}
 				}
 			}
 		}
-		if next_md != md
-			&& md.track_length_secs as f64 <= (Instant::now() - track_start).as_secs_f64()
-		{
-			md = next_md.clone();
-			verbose!("md: {:?}", md);
+		while let Some(notice) = monolib::take_notice() {
+			crossterm::execute!(stdout(), Clear(ClearType::CurrentLine), MoveToColumn(0)).unwrap();
+			println!("[{}]", notice);
 			crossterm::execute!(stdout(), Clear(ClearType::CurrentLine), MoveToColumn(0)).unwrap();
-			print!(
-				"Playing: {} - {} - {} (0:00 / {}:{:02})",
-				md.artist,
-				md.album,
-				md.title,
-				md.track_length_secs / 60,
-				md.track_length_secs % 60,
-			);
-			track_start = Instant::now();
-			seconds_past = 0;
-		} else if next_md == md {
-			next_md = monolib::get_metadata().unwrap();
+			// Redraw
+			seconds_past = u64::MAX;
+		}
+		let lost = (monolib::get_lost_fragments(), monolib::get_gap_frames());
+		if lost != losses {
+			losses = lost;
+			verbose!("{} fragments lost, {} frames in gaps", lost.0, lost.1);
+			// Redraw
+			seconds_past = u64::MAX;
+		}
+		// monolib switches metadata exactly when the next track starts playing
+		if let Some(next_md) = monolib::get_metadata().filter(|x| *x != md) {
+			md = next_md;
+			verbose!("md: {:?}", md);
+			// Redraw
+			seconds_past = u64::MAX;
 		}
-		if (Instant::now() - track_start).as_secs() > seconds_past {
-			seconds_past = (Instant::now() - track_start).as_secs();
+		let position = monolib::get_position().unwrap_or_default() as u64;
+		if position != seconds_past {
+			seconds_past = position;
 			crossterm::execute!(stdout(), Clear(ClearType::CurrentLine), MoveToColumn(0)).unwrap();
 			crossterm::execute!(
 				stdout(),
@@ -23,9 +23,16 @@ pub struct CSettings {
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 /// Starts audio playback using rodio
 /// Play without playlist => playlist = ""
-pub extern "C" fn c_start(server: *const c_char, settings: CSettings, playlist: *const c_char) {
+/// No token (public stations only) => token = NULL
+pub extern "C" fn c_start(
+	server: *const c_char,
+	settings: CSettings,
+	playlist: *const c_char,
+	token: *const c_char,
+) {
 	let serv = unsafe { CStr::from_ptr(server) };
 	let playlist = unsafe { CStr::from_ptr(playlist) };
+	let token = c_optional(token);
 	run(
 		serv.to_str().unwrap_or_default(),
 		Settings {
@@ -39,17 +46,61 @@ pub extern "C" fn c_start(server: *const c_char, settings: CSettings, playlist:
 				_ => return,
 			},
 			cover: settings.cover,
+			framing: None,
+			encryption: false,
 		},
 		playlist.to_str().unwrap_or_default(),
+		token.as_deref(),
 	)
 }
 
+fn c_optional(s: *const c_char) -> Option<String> {
+	if s.is_null() {
+		return None;
+	}
+	unsafe { CStr::from_ptr(s) }.to_str().ok().map(|x| x.to_string())
+}
+
+#[no_mangle]
+/// Trust settings for lonelyradios:// servers, see TlsOptions
+/// ca: additional CA certificates in PEM or NULL
+/// pin: SHA-256 of the server certificate in hex or NULL
+/// Returns false if ca or pin could not be parsed
+pub extern "C" fn c_set_tls_options(ca: *const c_char, pin: *const c_char) -> bool {
+	let pin = match c_optional(pin) {
+		Some(pin) => match parse_pin(&pin) {
+			Some(pin) => Some(pin),
+			None => return false,
+		},
+		None => None,
+	};
+	set_tls_options(TlsOptions {
+		ca: c_optional(ca).map(String::into_bytes),
+		pin,
+	})
+	.is_ok()
+}
+
+#[no_mangle]
+/// Key file for encrypted stations or NULL
+/// Returns false if the file could not be read
+pub extern "C" fn c_set_key_file(path: *const c_char) -> bool {
+	let key = match c_optional(path).map(std::fs::read) {
+		Some(Ok(key)) => Some(key),
+		Some(Err(_)) => return false,
+		None => None,
+	};
+	set_key(key);
+	true
+}
+
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 /// Playlists separated by '\n'
-pub extern "C" fn c_list_playlists(server: *const c_char) -> *mut c_char {
+/// No token (public playlists only) => token = NULL
+pub extern "C" fn c_list_playlists(server: *const c_char, token: *const c_char) -> *mut c_char {
 	let serv = unsafe { CStr::from_ptr(server) };
-	let playlists = list_playlists(serv.to_str().unwrap_or_default());
+	let playlists = list_playlists(serv.to_str().unwrap_or_default(), c_optional(token).as_deref());
 	CString::new(match playlists {
 		None => "".to_string(),
 		Some(s) => s.join("\n"),
@@ -58,6 +109,20 @@ pub extern "C" fn c_list_playlists(server: *const c_char) -> *mut c_char {
 	.into_raw()
 }
 
+#[no_mangle]
+/// Returns false if server does not support skipping without reconnecting
+pub extern "C" fn c_next() -> bool {
+	next()
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+/// Switch to the whole library => playlist = ""
+pub extern "C" fn c_set_playlist(playlist: *const c_char) -> bool {
+	let playlist = unsafe { CStr::from_ptr(playlist) };
+	set_playlist(playlist.to_str().unwrap_or_default())
+}
+
 #[no_mangle]
 pub extern "C" fn c_toggle() {
 	toggle()
@@ -110,6 +175,24 @@ pub extern "C" fn c_get_metadata_title() -> *mut c_char {
 	.into_raw()
 }
 
+/// Oldest not yet taken notice from the server as text, "" if there is none
+#[no_mangle]
+pub extern "C" fn c_take_notice() -> *mut c_char {
+	CString::new(take_notice().map(|x| x.to_string()).unwrap_or_default())
+		.unwrap_or_default()
+		.into_raw()
+}
+
+/// Stable track ID, 0 if nothing is playing or server is too old
+#[no_mangle]
+pub extern "C" fn c_get_metadata_id() -> u64 {
+	let md = MD.read().unwrap();
+	match md.as_ref() {
+		Some(md) => md.track_id,
+		None => 0,
+	}
+}
+
 #[no_mangle]
 pub extern "C" fn c_get_metadata_length() -> c_float {
 	let md = MD.read().unwrap();
@@ -119,6 +202,24 @@ pub extern "C" fn c_get_metadata_length() -> c_float {
 	}
 }
 
+/// Position in the playing track in seconds, -1 if nothing is playing
+#[no_mangle]
+pub extern "C" fn c_get_position() -> c_float {
+	get_position().map(|x| x as c_float).unwrap_or(-1.0)
+}
+
+/// Fragments lost since playback started
+#[no_mangle]
+pub extern "C" fn c_get_lost_fragments() -> u64 {
+	get_lost_fragments()
+}
+
+/// Frames skipped or repeated within tracks since playback started
+#[no_mangle]
+pub extern "C" fn c_get_gap_frames() -> u64 {
+	get_gap_frames()
+}
+
 #[repr(C)]
 pub struct CImageJpeg {
 	pub length: u32,
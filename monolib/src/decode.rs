@@ -1,18 +1,85 @@
 use std::io::{Cursor, Read};
 
+use anyhow::{bail, ensure, Context};
 use byteorder::{LittleEndian, ReadBytesExt};
-use lonelyradio_types::{Encoder, FragmentMetadata, TrackMetadata};
+use lonelyradio_types::{
+	read_frame, Cipher, Encoder, FragmentMetadata, Framing, PlayMessage, TrackMetadata,
+};
 use symphonia_core::{
 	audio::SampleBuffer,
 	codecs::{Decoder, CODEC_TYPE_ALAC},
 	formats::Packet,
 };
 
+use crate::{MAX_FRAGMENT, MAX_MESSAGE};
+
+/// Reads PlayMessages and fragments from the server, framed if it was
+/// negotiated and decrypted if the stream is sealed
+pub(crate) struct Receiver<R: Read> {
+	pub stream: R,
+	pub framing: Option<Framing>,
+	pub cipher: Option<Cipher>,
+}
+
+impl<R: Read> Receiver<R> {
+	pub fn message(&mut self) -> anyhow::Result<PlayMessage> {
+		match self.framing {
+			Some(f) => Ok(rmp_serde::from_slice(&self.frame(f.max_message.min(MAX_MESSAGE))?)?),
+			None => Ok(rmp_serde::from_read(&mut self.stream)?),
+		}
+	}
+
+	fn frame(&mut self, max: u32) -> anyhow::Result<Vec<u8>> {
+		let payload = read_frame(&mut self.stream, max)?;
+		match self.cipher.as_mut() {
+			Some(cipher) => cipher.open(&payload).context("failed to decrypt, wrong key?"),
+			None => Ok(payload),
+		}
+	}
+
+	pub fn fragment(
+		&mut self,
+		md: &TrackMetadata,
+		fmd: &FragmentMetadata,
+	) -> anyhow::Result<Vec<f32>> {
+		match self.framing {
+			Some(f) => {
+				let payload = self.frame(f.max_fragment.min(MAX_FRAGMENT))?;
+				ensure!(payload.len() as u64 == fmd.length, "fragment length mismatch");
+				decode(Cursor::new(payload), md, fmd)
+			}
+			None => decode(&mut self.stream, md, fmd),
+		}
+	}
+
+	/// Reads the fragment without decoding it
+	pub fn discard(&mut self, fmd: &FragmentMetadata) -> anyhow::Result<()> {
+		match self.framing {
+			Some(f) => {
+				self.frame(f.max_fragment.min(MAX_FRAGMENT))?;
+			}
+			None => {
+				check_length(fmd)?;
+				std::io::copy(&mut (&mut self.stream).take(fmd.length), &mut std::io::sink())?;
+			}
+		}
+		Ok(())
+	}
+}
+
+fn check_length(fmd: &FragmentMetadata) -> anyhow::Result<()> {
+	if fmd.length > MAX_FRAGMENT as u64 {
+		bail!("fragment of {} bytes exceeds the limit of {} bytes", fmd.length, MAX_FRAGMENT)
+	}
+	Ok(())
+}
+
 pub(crate) fn decode(
 	mut stream: impl ReadBytesExt,
 	md: &TrackMetadata,
 	fmd: &FragmentMetadata,
 ) -> anyhow::Result<Vec<f32>> {
+	check_length(fmd)?;
 	let mut samples = vec![];
 	match md.encoder {
 		Encoder::Pcm16 => {
@@ -95,3 +162,43 @@ pub(crate) fn decode(
 	};
 	Ok(samples)
 }
+
+#[cfg(test)]
+mod tests {
+	use lonelyradio_types::{write_frame, MIN_FRAME};
+
+	use super::*;
+
+	fn receiver(frames: &[usize], framing: Framing) -> Receiver<Cursor<Vec<u8>>> {
+		let mut stream = vec![];
+		for length in frames {
+			write_frame(&mut stream, &vec![0u8; *length]).unwrap();
+		}
+		Receiver {
+			stream: Cursor::new(stream),
+			framing: Some(framing.effective()),
+			cipher: None,
+		}
+	}
+
+	fn fragment(length: usize) -> FragmentMetadata {
+		FragmentMetadata {
+			length: length as u64,
+			magic_cookie: None,
+			offset: None,
+			sequence: None,
+		}
+	}
+
+	#[test]
+	fn small_limits_are_raised() {
+		let framing = Framing {
+			max_message: 1024,
+			max_fragment: 1024,
+		};
+		let length = MIN_FRAME as usize;
+		let mut receiver = receiver(&[length, length + 1], framing);
+		receiver.discard(&fragment(length)).unwrap();
+		assert!(receiver.discard(&fragment(length + 1)).is_err());
+	}
+}
@@ -1,15 +1,16 @@
 //! A library implementing the lonely radio audio streaming protocol
 //!
 //! Example usage (play for 10 seconds):
-//! ```rust
+//! ```no_run
 //! extern crate monolib;
 //! use std::thread::{sleep, spawn};
 //! use std::time::Duration;
 //! use monolib::lonelyradio_types::{Settings, Encoder};
 //!
-//! spawn(|| monolib::run("someserver:someport", Settings {encoder: Encoder::Flac, cover: -1}, "my_playlist"));
+//! spawn(|| monolib::run("someserver:someport", Settings {encoder: Encoder::Flac, cover: -1, framing: None, encryption: false}, "my_playlist", None));
 //! while monolib::get_metadata().is_none() {}
-//! let seconds = md.length / md.sample_rate as u64 / 2;
+//! let md = monolib::get_metadata().unwrap();
+//! let seconds = md.track_length_secs;
 //! println!("Playing: {} - {} - {} ({}:{:02})", md.artist, md.album, md.title, seconds / 60, seconds % 60);
 //! sleep(Duration::from_secs(10));
 //! monolib::stop();
@@ -21,23 +22,34 @@ pub mod c;
 pub use lonelyradio_types;
 
 use anyhow::{bail, Context};
-use decode::decode;
+use decode::Receiver;
 use lonelyradio_types::{
-	Encoder, PlayMessage, Request, RequestResult, ServerCapabilities, Settings, TrackMetadata,
+	auth_proof, Browse, Cipher, Control, Encoder, Feature, Framing, LibraryResponce, Notice, Page,
+	PlayMessage, Request, RequestKind, RequestResult, ServerCapabilities, Settings, StationInfo,
+	StatusResponce, TrackMetadata, HELLO_MAGIC, HELLO_MAGIC_VERSIONED, PROTOCOL_VERSION,
 };
 use rodio::buffer::SamplesBuffer;
 use rodio::{OutputStream, Sink};
+use std::collections::VecDeque;
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::atomic::AtomicU8;
-use std::sync::RwLock;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use transport::Connection;
 
 mod decode;
+mod transport;
+
+pub use transport::{parse_pin, set_tls_options, TlsOptions, TLS_SCHEME, UNIX_PREFIX};
 
 const CACHE_SIZE_PCM: usize = 32;
 const CACHE_SIZE_COMPRESSED: usize = 4;
 
+/// Largest PlayMessage accepted in framed mode (covers included)
+pub const MAX_MESSAGE: u32 = 16 * 1024 * 1024;
+/// Largest fragment accepted, bigger ones are treated as a broken stream
+pub const MAX_FRAGMENT: u32 = 4 * 1024 * 1024;
+
 pub const SUPPORTED_DECODERS: &[Encoder] = &[
 	#[cfg(feature = "flac")]
 	Encoder::Flac,
@@ -54,7 +66,41 @@ pub const SUPPORTED_DECODERS: &[Encoder] = &[
 static SINK: RwLock<Option<Sink>> = RwLock::new(None);
 static VOLUME: AtomicU8 = AtomicU8::new(255);
 static MD: RwLock<Option<TrackMetadata>> = RwLock::new(None);
+static STATION: RwLock<Option<StationInfo>> = RwLock::new(None);
 static STATE: RwLock<State> = RwLock::new(State::NotStarted);
+// Write half of the connection, if server accepts control messages
+static CONTROL: Mutex<Option<Connection>> = Mutex::new(None);
+// Fragments are dropped until the next track starts
+static SKIPPING: AtomicBool = AtomicBool::new(false);
+// Buffers appended to the sink and not finished yet, the first one is playing
+static QUEUE: Mutex<VecDeque<Buffer>> = Mutex::new(VecDeque::new());
+static PLAYHEAD: Mutex<Option<Playhead>> = Mutex::new(None);
+// Received, but not yet taken notices
+static NOTICES: Mutex<VecDeque<Notice>> = Mutex::new(VecDeque::new());
+static KEY: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+// Since playback started, see get_lost_fragments() and get_gap_frames()
+static LOST_FRAGMENTS: AtomicU64 = AtomicU64::new(0);
+static GAP_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+struct Buffer {
+	// Metadata of the track, which starts with this buffer
+	md: Option<TrackMetadata>,
+	// In frames from the start of the track
+	offset: u64,
+	frames: u64,
+	sample_rate: u32,
+}
+
+// Currently playing buffer
+struct Playhead {
+	offset: u64,
+	frames: u64,
+	sample_rate: u32,
+	// Played before the last pause
+	played: Duration,
+	// Playing since (None if paused)
+	since: Option<Instant>,
+}
 
 /// Player state
 #[derive(Clone, Copy, PartialEq)]
@@ -76,6 +122,9 @@ pub fn toggle() {
 		if let Some(sink) = sink.as_ref() {
 			sink.pause()
 		}
+		if let Some(playhead) = PLAYHEAD.lock().unwrap().as_mut() {
+			playhead.played += playhead.since.take().map(|x| x.elapsed()).unwrap_or_default();
+		}
 	} else if *state == State::Paused {
 		*state = State::Playing;
 
@@ -83,6 +132,9 @@ pub fn toggle() {
 		if let Some(sink) = sink.as_ref() {
 			sink.play()
 		}
+		if let Some(playhead) = PLAYHEAD.lock().unwrap().as_mut() {
+			playhead.since = Some(Instant::now());
+		}
 	}
 }
 
@@ -108,42 +160,158 @@ pub fn stop() {
 	}
 }
 
+/// Skips to the next track without reconnecting.
+/// Returns false if server does not support it, `stop()` and `run()` then
+pub fn next() -> bool {
+	skip_with(Control::Skip)
+}
+
+/// Starts the current track from the beginning without reconnecting
+pub fn replay() -> bool {
+	skip_with(Control::Replay)
+}
+
+/// Switches to another playlist ("" for the whole library) without
+/// reconnecting
+pub fn set_playlist(playlist: &str) -> bool {
+	skip_with(Control::ChangePlaylist(playlist.to_string()))
+}
+
+/// Continues the current track with another encoder
+pub fn set_encoder(encoder: Encoder) -> bool {
+	SUPPORTED_DECODERS.contains(&encoder) && send_control(Control::ChangeEncoder(encoder))
+}
+
+fn send_control(control: Control) -> bool {
+	let mut connection = CONTROL.lock().unwrap();
+	match connection.as_mut() {
+		Some(c) => c.write_all(&rmp_serde::to_vec_named(&control).unwrap()).is_ok(),
+		None => false,
+	}
+}
+
+// Drops buffered audio and everything until the next track
+fn skip_with(control: Control) -> bool {
+	SKIPPING.store(true, std::sync::atomic::Ordering::Release);
+	if !send_control(control) {
+		SKIPPING.store(false, std::sync::atomic::Ordering::Release);
+		return false;
+	}
+	let sink = SINK.read().unwrap();
+	if let Some(sink) = sink.as_ref() {
+		let mut queue = QUEUE.lock().unwrap();
+		sink.clear();
+		queue.clear();
+		*PLAYHEAD.lock().unwrap() = None;
+		if get_state() == State::Playing {
+			sink.play()
+		}
+	}
+	true
+}
+
 pub fn get_state() -> State {
 	*STATE.read().unwrap()
 }
 
+/// Metadata of the track, which is playing now (not the one being received)
 pub fn get_metadata() -> Option<TrackMetadata> {
+	sync();
 	MD.read().unwrap().clone()
 }
 
+/// Info about the station playing now, if server has it configured
+pub fn get_station() -> Option<StationInfo> {
+	STATION.read().unwrap().clone()
+}
+
+/// Oldest notice from the server (announcement, station change or shutdown),
+/// which was not taken yet
+pub fn take_notice() -> Option<Notice> {
+	NOTICES.lock().unwrap().pop_front()
+}
+
+/// Fragments the server sent, but which never arrived, since playback started.
+/// Only servers sending sequence numbers are checked
+pub fn get_lost_fragments() -> u64 {
+	LOST_FRAGMENTS.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Frames skipped or repeated within tracks since playback started. Only
+/// servers sending offsets are checked
+pub fn get_gap_frames() -> u64 {
+	GAP_FRAMES.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Position in the playing track in seconds
+pub fn get_position() -> Option<f64> {
+	sync();
+	let playhead = PLAYHEAD.lock().unwrap();
+	playhead.as_ref().map(|p| {
+		let elapsed = p.played + p.since.map(|x| x.elapsed()).unwrap_or_default();
+		let frames = (elapsed.as_secs_f64() * p.sample_rate as f64).min(p.frames as f64);
+		(p.offset as f64 + frames) / p.sample_rate as f64
+	})
+}
+
+fn sync() {
+	if let Some(sink) = SINK.read().unwrap().as_ref() {
+		sync_with(sink)
+	}
+}
+
+// Drops buffers finished by the sink, switching metadata when the next track
+// starts playing
+fn sync_with(sink: &Sink) {
+	let mut queue = QUEUE.lock().unwrap();
+	let mut playhead = PLAYHEAD.lock().unwrap();
+	let finished = queue.len().saturating_sub(sink.len());
+	if finished == 0 && playhead.is_some() {
+		return;
+	}
+	queue.drain(..finished);
+	let Some(buffer) = queue.front_mut() else {
+		*playhead = None;
+		return;
+	};
+	if let Some(md) = buffer.md.take() {
+		*MD.write().unwrap() = Some(md);
+	}
+	*playhead = Some(Playhead {
+		offset: buffer.offset,
+		frames: buffer.frames,
+		sample_rate: buffer.sample_rate,
+		played: Duration::ZERO,
+		since: (!sink.is_paused()).then(Instant::now),
+	});
+}
+
 fn _stop() {
+	*CONTROL.lock().unwrap() = None;
+	SKIPPING.store(false, std::sync::atomic::Ordering::Release);
 	let sink = SINK.read().unwrap();
 	if let Some(sink) = sink.as_ref() {
+		let mut queue = QUEUE.lock().unwrap();
 		sink.clear();
+		queue.clear();
 	}
+	drop(sink);
+	*PLAYHEAD.lock().unwrap() = None;
 	let mut md = MD.write().unwrap();
 	if md.is_some() {
 		*md = None;
 	}
+	*STATION.write().unwrap() = None;
 
 	*STATE.write().unwrap() = State::NotStarted;
 }
 
 // Reset - true, not - false
-fn watching_sleep(dur: f32) -> bool {
+fn watching_sleep(dur: f32, sink: &Sink) -> bool {
 	let start = Instant::now();
 	while Instant::now() < start + std::time::Duration::from_secs_f32(dur) {
 		std::thread::sleep(std::time::Duration::from_secs_f32(0.01));
-		if *STATE.read().unwrap() == State::Resetting {
-			return true;
-		}
-	}
-	false
-}
-
-fn watching_sleep_until_end() -> bool {
-	while SINK.read().unwrap().as_ref().unwrap().len() != 0 {
-		std::thread::sleep(std::time::Duration::from_secs_f32(0.01));
+		sync_with(sink);
 		if *STATE.read().unwrap() == State::Resetting {
 			return true;
 		}
@@ -163,46 +331,143 @@ pub fn set_volume(volume: u8) {
 	VOLUME.store(volume, std::sync::atomic::Ordering::Relaxed)
 }
 
-/// Download track as samples
+/// Connects to the server and authenticates with `token`, if the server has
+/// private stations
+fn connect(server: &str, token: Option<&str>) -> anyhow::Result<(Connection, ServerCapabilities)> {
+	let (mut connection, capabilities) = handshake(server)?;
+	if let (Some(challenge), Some(token)) = (capabilities.challenge.as_ref(), token) {
+		let request = Request::Authenticate(auth_proof(token, challenge));
+		connection.write_all(&rmp_serde::to_vec_named(&request)?)?;
+		if let RequestResult::Error(e) = rmp_serde::from_read(&mut connection)? {
+			bail!("{e:?}")
+		}
+	}
+	Ok((connection, capabilities))
+}
+
+/// Connects to the server and negotiates protocol version. Servers predating
+/// versioning drop the connection on the new hello, so the legacy one is
+/// tried after that
+fn handshake(server: &str) -> anyhow::Result<(Connection, ServerCapabilities)> {
+	let mut connection = Connection::open(server)?;
+	connection.write_all(HELLO_MAGIC_VERSIONED)?;
+	connection.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+	if let Ok(capabilities) = rmp_serde::from_read(&mut connection) {
+		return Ok((connection, capabilities));
+	}
+	let mut connection = Connection::open(server)?;
+	connection.write_all(HELLO_MAGIC)?;
+	let capabilities = rmp_serde::from_read(&mut connection)?;
+	Ok((connection, capabilities))
+}
+
+/// Requests framing with monolib's limits (unless other limits were set), if
+/// the server supports it
+fn negotiate_framing(settings: &mut Settings, capabilities: &ServerCapabilities) {
+	settings.framing = if capabilities.has(Feature::Framing) {
+		settings.framing.or(Some(Framing {
+			max_message: MAX_MESSAGE,
+			max_fragment: MAX_FRAGMENT,
+		}))
+	} else {
+		None
+	};
+}
+
+/// Asks for encryption, if the server has encrypted stations and the key is
+/// set. Encrypted streams are always framed
+fn negotiate_encryption(settings: &mut Settings, capabilities: &ServerCapabilities) {
+	settings.encryption = capabilities.has(Feature::Encryption) && KEY.read().unwrap().is_some();
+	if settings.encryption {
+		settings.framing = settings.framing.or(Some(Framing {
+			max_message: MAX_MESSAGE,
+			max_fragment: MAX_FRAGMENT,
+		}))
+	}
+}
+
+/// Cipher for the stream, if the server has sealed it
+fn unseal(response: RequestResult) -> anyhow::Result<Option<Cipher>> {
+	match response {
+		RequestResult::Error(e) => bail!("{e:?}"),
+		RequestResult::Sealed(salt) => {
+			let key = KEY.read().unwrap();
+			let key = key.as_ref().context("stream is encrypted, but the key is not set")?;
+			Ok(Some(Cipher::new(key, &salt)))
+		}
+		_ => Ok(None),
+	}
+}
+
+/// Key (contents of the key file) for encrypted stations, used by connections
+/// made after the call
+pub fn set_key(key: Option<Vec<u8>>) {
+	*KEY.write().unwrap() = key
+}
+
+// Pcm16, unless it can't fit into the server's bandwidth ceiling
+fn fallback_encoder(capabilities: &ServerCapabilities) -> Encoder {
+	if capabilities.encoders.contains(&Encoder::Pcm16) {
+		return Encoder::Pcm16;
+	}
+	SUPPORTED_DECODERS
+		.iter()
+		.copied()
+		.find(|x| capabilities.encoders.contains(x))
+		.unwrap_or(Encoder::Pcm16)
+}
+
+/// Capabilities of the server at "server:port"
+pub fn get_capabilities(server: &str) -> anyhow::Result<ServerCapabilities> {
+	handshake(server).map(|(_, capabilities)| capabilities)
+}
+
+/// Download track as samples. `token` is only needed for private stations
 pub fn get_track(
 	server: &str,
 	mut settings: Settings,
 	playlist: &str,
+	token: Option<&str>,
 ) -> anyhow::Result<(TrackMetadata, Vec<f32>)> {
-	let mut connection = TcpStream::connect(server)?;
-	connection.write_all(lonelyradio_types::HELLO_MAGIC)?;
-	let capabilities: ServerCapabilities = rmp_serde::from_read(&mut connection)?;
+	let (mut connection, capabilities) = connect(server, token)?;
 	if !capabilities.encoders.contains(&settings.encoder) {
-		settings.encoder = Encoder::Pcm16
+		settings.encoder = fallback_encoder(&capabilities)
 	}
+	negotiate_framing(&mut settings, &capabilities);
+	negotiate_encryption(&mut settings, &capabilities);
+	// The server raises small limits, frames up to them are valid
+	let framing = settings.framing.map(Framing::effective);
 
 	let request = if playlist.is_empty() {
 		Request::Play(settings)
 	} else {
 		Request::PlayPlaylist(playlist.to_string(), settings)
 	};
+	if !capabilities.supports(request.kind()) {
+		bail!("server does not support {:?} requests", request.kind())
+	}
 	connection.write_all(&rmp_serde::to_vec_named(&request).unwrap())?;
 
-	let response: RequestResult = rmp_serde::from_read(&connection)?;
-	if let RequestResult::Error(e) = response {
-		bail!("{e:?}")
-	}
+	let cipher = unseal(rmp_serde::from_read(&mut connection)?)?;
 
 	let mut samples = vec![];
 	let mut md: Option<TrackMetadata> = None;
+	let mut receiver = Receiver {
+		stream: connection,
+		framing,
+		cipher,
+	};
 
 	loop {
-		let recv_md: PlayMessage = rmp_serde::from_read(&mut connection)?;
-		match recv_md {
+		match receiver.message()? {
 			PlayMessage::T(tmd) => {
 				if md.is_some() {
 					break;
 				}
 				md = Some(tmd);
 			}
-			PlayMessage::F(fmd) => {
-				samples.extend(decode(&mut connection, md.as_ref().unwrap(), &fmd)?)
-			}
+			PlayMessage::F(fmd) => samples.extend(receiver.fragment(md.as_ref().unwrap(), &fmd)?),
+			PlayMessage::N(_) => {}
 		}
 	}
 
@@ -213,10 +478,12 @@ pub fn get_track(
 	}
 }
 
-pub fn list_playlists(server: &str) -> Option<Vec<String>> {
-	let mut connection = TcpStream::connect(server).ok()?;
-	connection.write_all(lonelyradio_types::HELLO_MAGIC).ok()?;
-	let _: ServerCapabilities = rmp_serde::from_read(&mut connection).ok()?;
+/// Private playlists are only listed with a `token` giving access to them
+pub fn list_playlists(server: &str, token: Option<&str>) -> Option<Vec<String>> {
+	let (mut connection, capabilities) = connect(server, token).ok()?;
+	if !capabilities.supports(RequestKind::ListPlaylist) {
+		return None;
+	}
 	connection.write_all(&rmp_serde::to_vec_named(&Request::ListPlaylist).ok()?).ok()?;
 	let res: RequestResult = rmp_serde::from_read(connection).ok()?;
 	match res {
@@ -225,16 +492,115 @@ pub fn list_playlists(server: &str) -> Option<Vec<String>> {
 	}
 }
 
-/// Starts playing at "server:port"
-pub fn run(server: &str, settings: Settings, playlist: &str) {
-	let result = _run(server, settings, playlist);
+/// Full-text search over title, artist and album of the server's library
+pub fn search(
+	server: &str,
+	query: &str,
+	page: Page,
+	token: Option<&str>,
+) -> anyhow::Result<LibraryResponce> {
+	library_request(server, Request::Search(query.to_string(), page), token)
+}
+
+/// Lists artists, albums of an artist or tracks of an album
+pub fn browse(
+	server: &str,
+	browse: Browse,
+	page: Page,
+	token: Option<&str>,
+) -> anyhow::Result<LibraryResponce> {
+	library_request(server, Request::Browse(browse, page), token)
+}
+
+fn library_request(
+	server: &str,
+	request: Request,
+	token: Option<&str>,
+) -> anyhow::Result<LibraryResponce> {
+	let (mut connection, capabilities) = connect(server, token)?;
+	if !capabilities.supports(request.kind()) {
+		bail!("server does not support {:?} requests", request.kind())
+	}
+	connection.write_all(&rmp_serde::to_vec_named(&request)?)?;
+	match rmp_serde::from_read(&mut connection)? {
+		RequestResult::Library(library) => Ok(library),
+		RequestResult::Error(e) => bail!("{e:?}"),
+		r => bail!("unexpected response: {r:?}"),
+	}
+}
+
+/// Listeners, recently played tracks and uptime of the server without
+/// starting playback. Private stations are only included with a `token` giving
+/// access to them
+pub fn status(server: &str, token: Option<&str>) -> anyhow::Result<StatusResponce> {
+	let (mut connection, capabilities) = connect(server, token)?;
+	if !capabilities.supports(RequestKind::Status) {
+		bail!("server does not support {:?} requests", RequestKind::Status)
+	}
+	connection.write_all(&rmp_serde::to_vec_named(&Request::Status)?)?;
+	match rmp_serde::from_read(&mut connection)? {
+		RequestResult::Status(status) => Ok(status),
+		RequestResult::Error(e) => bail!("{e:?}"),
+		r => bail!("unexpected response: {r:?}"),
+	}
+}
+
+/// What to play
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+	/// Shuffle the whole library
+	Station,
+	Playlist(String),
+	/// Track by its ID (see `search()` and `browse()`)
+	Track(u64),
+	/// Album (artist, album) in order
+	Album(String, String),
+	/// Shuffle all tracks of the artist
+	Artist(String),
+}
+
+impl Selection {
+	fn request(self, settings: Settings) -> Request {
+		match self {
+			Selection::Station => Request::Play(settings),
+			Selection::Playlist(playlist) => Request::PlayPlaylist(playlist, settings),
+			Selection::Track(id) => Request::PlayTrack(id, settings),
+			Selection::Album(artist, album) => Request::PlayAlbum(artist, album, settings),
+			Selection::Artist(artist) => Request::PlayArtist(artist, settings),
+		}
+	}
+}
+
+/// Starts playing at "server:port". `token` is only needed for private stations
+pub fn run(server: &str, settings: Settings, playlist: &str, token: Option<&str>) {
+	play(
+		server,
+		settings,
+		if playlist.is_empty() {
+			Selection::Station
+		} else {
+			Selection::Playlist(playlist.to_string())
+		},
+		token,
+	)
+}
+
+/// Starts playing selected tracks at "server:port". When they are over server
+/// continues with the whole library shuffle
+pub fn play(server: &str, settings: Settings, selection: Selection, token: Option<&str>) {
+	let result = _run(server, settings, selection, token);
 	if let Err(e) = result {
 		println!("{:?}", e);
 		*STATE.write().unwrap() = State::NotStarted;
 	}
 }
 
-pub(crate) fn _run(server: &str, mut settings: Settings, playlist: &str) -> anyhow::Result<()> {
+pub(crate) fn _run(
+	server: &str,
+	mut settings: Settings,
+	selection: Selection,
+	token: Option<&str>,
+) -> anyhow::Result<()> {
 	if !SUPPORTED_DECODERS.contains(&settings.encoder) {
 		eprintln!(
 			"monolib was built without support for {:?}, falling back to Pcm16",
@@ -248,26 +614,34 @@ pub(crate) fn _run(server: &str, mut settings: Settings, playlist: &str) -> anyh
 	}
 	*state = State::Playing;
 	drop(state);
+	LOST_FRAGMENTS.store(0, std::sync::atomic::Ordering::Relaxed);
+	GAP_FRAMES.store(0, std::sync::atomic::Ordering::Relaxed);
 
-	let mut connection = TcpStream::connect(server).context("failed to connect to the server")?;
-	connection.write_all(lonelyradio_types::HELLO_MAGIC)?;
-	let capabilities: ServerCapabilities = rmp_serde::from_read(&mut connection)?;
+	let (mut connection, capabilities) = connect(server, token)?;
 	if !capabilities.encoders.contains(&settings.encoder) {
-		settings.encoder = Encoder::Pcm16
+		settings.encoder = fallback_encoder(&capabilities)
+	}
+	negotiate_framing(&mut settings, &capabilities);
+	negotiate_encryption(&mut settings, &capabilities);
+	// The server raises small limits, frames up to them are valid
+	let framing = settings.framing.map(Framing::effective);
+
+	let request = selection.request(settings);
+	if !capabilities.supports(request.kind()) {
+		bail!("server does not support {:?} requests", request.kind())
 	}
-
-	let request = if playlist.is_empty() {
-		Request::Play(settings)
-	} else {
-		Request::PlayPlaylist(playlist.to_string(), settings)
-	};
 	connection.write_all(&rmp_serde::to_vec_named(&request).unwrap())?;
 
-	let response: RequestResult = rmp_serde::from_read(&connection).unwrap();
-	if let RequestResult::Error(e) = response {
-		bail!("{:?}", e)
+	let cipher = unseal(rmp_serde::from_read(&mut connection)?)?;
+	if capabilities.has(Feature::Control) {
+		*CONTROL.lock().unwrap() = connection.try_clone();
 	}
-	let mut stream = connection;
+	*STATION.write().unwrap() = capabilities.station;
+	let mut receiver = Receiver {
+		stream: connection,
+		framing,
+		cipher,
+	};
 
 	let mut sink = SINK.write().unwrap();
 	let (_stream, stream_handle) =
@@ -279,21 +653,25 @@ pub(crate) fn _run(server: &str, mut settings: Settings, playlist: &str) -> anyh
 	drop(sink);
 
 	let mut samples = Vec::with_capacity(8192);
+	// Track being received, not played
+	let mut md: Option<TrackMetadata> = None;
+	let mut new_track = false;
+	let mut continuity = Continuity::default();
 	loop {
-		let recv_md: PlayMessage =
-			rmp_serde::from_read(&mut stream).expect("Failed to parse message");
+		let recv_md = receiver.message().context("failed to read message")?;
 		match recv_md {
 			PlayMessage::T(tmd) => {
-				// No metadata shift
-				if watching_sleep_until_end() {
+				SKIPPING.store(false, std::sync::atomic::Ordering::Release);
+				if *STATE.read().unwrap() == State::Resetting {
 					_stop();
 					return Ok(());
 				}
-				let mut md = MD.write().unwrap();
-				*md = Some(tmd.clone());
-
-				drop(md);
+				// Shown when its first buffer starts playing (see sync_with())
+				md = Some(tmd);
+				new_track = true;
+				continuity.new_track();
 			}
+			PlayMessage::N(notice) => NOTICES.lock().unwrap().push_back(notice),
 			PlayMessage::F(fmd) => {
 				while *STATE.read().unwrap() == State::Paused {
 					std::thread::sleep(std::time::Duration::from_secs_f32(0.25))
@@ -302,14 +680,21 @@ pub(crate) fn _run(server: &str, mut settings: Settings, playlist: &str) -> anyh
 					_stop();
 					return Ok(());
 				}
+				LOST_FRAGMENTS
+					.fetch_add(continuity.lost(fmd.sequence), std::sync::atomic::Ordering::Relaxed);
+				if SKIPPING.load(std::sync::atomic::Ordering::Acquire) {
+					receiver.discard(&fmd)?;
+					continue;
+				}
 
-				samples.extend(decode(&mut stream, &MD.read().unwrap().clone().unwrap(), &fmd)?);
+				let md = md.as_ref().context("fragment before track metadata")?;
+				samples.extend(receiver.fragment(md, &fmd)?);
+				let frames = (samples.len() / md.channels as usize) as u64;
+				let (offset, gap) = continuity.gap(fmd.offset, frames, new_track);
+				GAP_FRAMES.fetch_add(gap, std::sync::atomic::Ordering::Relaxed);
 
 				// Synchronizing with sink
 				let sink = SINK.read().unwrap();
-				let _md = MD.read().unwrap();
-				let md = _md.as_ref().unwrap().clone();
-				drop(_md);
 				if let Some(sink) = sink.as_ref() {
 					while (sink.len() >= CACHE_SIZE_PCM
 						&& md.encoder == Encoder::Pcm16
@@ -326,19 +711,166 @@ pub(crate) fn _run(server: &str, mut settings: Settings, playlist: &str) -> anyh
 								0.25
 							} * samples.len() as f32 / md.sample_rate as f32
 								/ 4.0,
+							sink,
 						) {
 							_stop();
 							return Ok(());
 						}
 					}
+					let mut queue = QUEUE.lock().unwrap();
 					sink.append(SamplesBuffer::new(
 						md.channels,
 						md.sample_rate,
 						samples.as_slice(),
 					));
+					queue.push_back(Buffer {
+						md: new_track.then(|| md.clone()),
+						offset,
+						frames,
+						sample_rate: md.sample_rate,
+					});
+					drop(queue);
+					new_track = false;
 					samples.clear();
+					sync_with(sink);
 				}
 			}
 		}
 	}
 }
+
+/// Sequence numbers and offsets of received fragments
+#[derive(Default)]
+struct Continuity {
+	next_sequence: Option<u64>,
+	/// In frames from the start of the track
+	next_offset: u64,
+}
+
+impl Continuity {
+	fn new_track(&mut self) {
+		self.next_offset = 0;
+	}
+
+	/// Fragments lost right before this one. Lower sequence (repeated fragment
+	/// or restarted numbering) isn't a loss, numbering continues from it
+	fn lost(&mut self, sequence: Option<u64>) -> u64 {
+		let lost = match (self.next_sequence, sequence) {
+			(Some(expected), Some(sequence)) => sequence.saturating_sub(expected),
+			_ => 0,
+		};
+		self.next_sequence = sequence.map(|x| x + 1);
+		lost
+	}
+
+	/// Offset of the fragment and frames skipped or repeated before it. Older
+	/// servers don't send offsets, the first fragment of a track may start
+	/// anywhere (when it's resumed with another encoder)
+	fn gap(&mut self, offset: Option<u64>, frames: u64, first: bool) -> (u64, u64) {
+		let offset = offset.unwrap_or(self.next_offset);
+		let gap = if first {
+			0
+		} else {
+			offset.abs_diff(self.next_offset)
+		};
+		self.next_offset = offset + frames;
+		(offset, gap)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Read;
+	use std::net::TcpListener;
+
+	use super::*;
+
+	/// Server answering `responses` hellos, one connection each. None closes the
+	/// connection without answering
+	fn server(responses: Vec<Option<ServerCapabilities>>) -> (String, std::thread::JoinHandle<()>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		let server = std::thread::spawn(move || {
+			for response in responses {
+				let (mut s, _) = listener.accept().unwrap();
+				let mut hello = [0u8; 8];
+				s.read_exact(&mut hello).unwrap();
+				match response {
+					Some(capabilities) => {
+						if &hello == HELLO_MAGIC_VERSIONED {
+							s.read_exact(&mut [0u8; 2]).unwrap();
+						}
+						s.write_all(&rmp_serde::to_vec_named(&capabilities).unwrap()).unwrap();
+					}
+					None => assert_eq!(&hello, HELLO_MAGIC_VERSIONED),
+				}
+			}
+		});
+		(address, server)
+	}
+
+	fn capabilities(version: u16) -> ServerCapabilities {
+		ServerCapabilities {
+			encoders: vec![Encoder::Pcm16],
+			max_bandwidth: None,
+			version,
+			available_requests: vec![RequestKind::Play],
+			features: vec![],
+			station: None,
+			challenge: None,
+		}
+	}
+
+	#[test]
+	fn lost_fragments() {
+		let mut continuity = Continuity::default();
+		let lost: Vec<u64> =
+			[0, 1, 2, 5, 6, 9].into_iter().map(|x| continuity.lost(Some(x))).collect();
+		assert_eq!(lost, [0, 0, 0, 2, 0, 2]);
+	}
+
+	#[test]
+	fn sequence_going_backwards() {
+		let mut continuity = Continuity::default();
+		// Repeated, then restarted, then continuing from there
+		let lost: Vec<u64> =
+			[4, 5, 5, 0, 1, 3].into_iter().map(|x| continuity.lost(Some(x))).collect();
+		assert_eq!(lost, [0, 0, 0, 0, 0, 1]);
+		// Older servers don't number fragments
+		assert_eq!(continuity.lost(None), 0);
+		assert_eq!(continuity.lost(Some(10)), 0);
+	}
+
+	#[test]
+	fn gaps() {
+		let mut continuity = Continuity::default();
+		assert_eq!(continuity.gap(Some(0), 100, true), (0, 0));
+		assert_eq!(continuity.gap(Some(100), 100, false), (100, 0));
+		// Skipped
+		assert_eq!(continuity.gap(Some(250), 100, false), (250, 50));
+		// Repeated
+		assert_eq!(continuity.gap(Some(300), 100, false), (300, 50));
+		// Without offsets fragments follow each other
+		assert_eq!(continuity.gap(None, 100, false), (400, 0));
+		// Resumed track
+		continuity.new_track();
+		assert_eq!(continuity.gap(Some(1000), 100, true), (1000, 0));
+		continuity.new_track();
+		assert_eq!(continuity.gap(None, 100, true), (0, 0));
+	}
+
+	#[test]
+	fn versioned_server() {
+		let (address, server) = server(vec![Some(capabilities(1))]);
+		assert_eq!(get_capabilities(&address).unwrap(), capabilities(1));
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn legacy_server() {
+		// Drops the connection on the new hello and only knows the legacy one
+		let (address, server) = server(vec![None, Some(capabilities(0))]);
+		assert_eq!(get_capabilities(&address).unwrap(), capabilities(0));
+		server.join().unwrap();
+	}
+}
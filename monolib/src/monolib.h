@@ -19,13 +19,39 @@ typedef struct CImageJpeg {
 /**
  * Starts audio playback using rodio
  * Play without playlist => playlist = ""
+ * No token (public stations only) => token = NULL
  */
-void c_start(const char *server, struct CSettings settings, const char *playlist);
+void c_start(const char *server, struct CSettings settings, const char *playlist, const char *token);
+
+/**
+ * Trust settings for lonelyradios:// servers, see TlsOptions
+ * ca: additional CA certificates in PEM or NULL
+ * pin: SHA-256 of the server certificate in hex or NULL
+ * Returns false if ca or pin could not be parsed
+ */
+bool c_set_tls_options(const char *ca, const char *pin);
+
+/**
+ * Key file for encrypted stations or NULL
+ * Returns false if the file could not be read
+ */
+bool c_set_key_file(const char *path);
 
 /**
  * Playlists separated by '\n'
+ * No token (public playlists only) => token = NULL
+ */
+char *c_list_playlists(const char *server, const char *token);
+
+/**
+ * Returns false if server does not support skipping without reconnecting
  */
-char *c_list_playlists(const char *server);
+bool c_next(void);
+
+/**
+ * Switch to the whole library => playlist = ""
+ */
+bool c_set_playlist(const char *playlist);
 
 void c_toggle(void);
 
@@ -39,8 +65,33 @@ char *c_get_metadata_album(void);
 
 char *c_get_metadata_title(void);
 
+/**
+ * Oldest not yet taken notice from the server as text, "" if there is none
+ */
+char *c_take_notice(void);
+
+/**
+ * Stable track ID, 0 if nothing is playing or server is too old
+ */
+uint64_t c_get_metadata_id(void);
+
 float c_get_metadata_length(void);
 
+/**
+ * Position in the playing track in seconds, -1 if nothing is playing
+ */
+float c_get_position(void);
+
+/**
+ * Fragments lost since playback started
+ */
+uint64_t c_get_lost_fragments(void);
+
+/**
+ * Frames skipped or repeated within tracks since playback started
+ */
+uint64_t c_get_gap_frames(void);
+
 /**
  * # Safety
  * Manually deallocate returned memory after use
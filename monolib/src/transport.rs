@@ -0,0 +1,338 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Context;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore};
+use sha2::{Digest, Sha256};
+
+/// Addresses with this prefix are connected to over TLS
+pub const TLS_SCHEME: &str = "lonelyradios://";
+/// Addresses with this prefix are Unix socket paths
+pub const UNIX_PREFIX: &str = "unix:";
+const SCHEME: &str = "lonelyradio://";
+
+static TLS: RwLock<Option<Arc<ClientConfig>>> = RwLock::new(None);
+
+/// Trust settings for `lonelyradios://` servers
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+	/// Additional trusted CA certificates (PEM), e.g. of a home server
+	pub ca: Option<Vec<u8>>,
+	/// SHA-256 of the server certificate. If set, it is the only accepted
+	/// one and neither CA nor hostname are checked
+	pub pin: Option<[u8; 32]>,
+}
+
+/// Applies to connections made after the call. Without it only publicly
+/// trusted certificates are accepted
+pub fn set_tls_options(options: TlsOptions) -> anyhow::Result<()> {
+	let config = match options.pin {
+		Some(pin) => ClientConfig::builder()
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(Pinned {
+				pin,
+				provider: provider(),
+			}))
+			.with_no_client_auth(),
+		None => {
+			let mut roots =
+				RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+			for cert in CertificateDer::pem_slice_iter(options.ca.as_deref().unwrap_or_default()) {
+				roots.add(cert.context("failed to parse CA certificate")?)?;
+			}
+			ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()
+		}
+	};
+	*TLS.write().unwrap() = Some(Arc::new(config));
+	Ok(())
+}
+
+/// Parses certificate fingerprint as printed by the server ("ab:cd:..." or
+/// plain hex)
+pub fn parse_pin(pin: &str) -> Option<[u8; 32]> {
+	let hex: Vec<char> = pin.chars().filter(|x| *x != ':').collect();
+	if hex.len() != 64 {
+		return None;
+	}
+	let mut bytes = [0u8; 32];
+	for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+		*byte = u8::from_str_radix(&pair.iter().collect::<String>(), 16).ok()?;
+	}
+	Some(bytes)
+}
+
+/// Connection to the server: plain, TLS or over a Unix socket
+pub(crate) enum Connection {
+	Tcp(TcpStream),
+	#[cfg(unix)]
+	Unix(UnixStream),
+	Tls(Tls),
+}
+
+/// TLS state is shared with the control handle, as it can't be split into
+/// halves. It's only locked to process data, never while waiting for the
+/// socket to be readable, so control messages aren't held up by reads
+pub(crate) struct Tls {
+	connection: Arc<Mutex<ClientConnection>>,
+	socket: TcpStream,
+}
+
+impl Tls {
+	fn connect(mut socket: TcpStream, mut connection: ClientConnection) -> anyhow::Result<Self> {
+		while connection.is_handshaking() {
+			if connection.complete_io(&mut socket)? == (0, 0) {
+				anyhow::bail!("connection closed during TLS handshake")
+			}
+		}
+		Ok(Self {
+			connection: Arc::new(Mutex::new(connection)),
+			socket,
+		})
+	}
+
+	fn try_clone(&self) -> Option<Self> {
+		Some(Self {
+			connection: self.connection.clone(),
+			socket: self.socket.try_clone().ok()?,
+		})
+	}
+
+	/// Sends records the connection has queued (e.g. alerts and key updates)
+	fn send_queued(&mut self, connection: &mut ClientConnection) -> std::io::Result<()> {
+		while connection.wants_write() {
+			connection.write_tls(&mut self.socket)?;
+		}
+		Ok(())
+	}
+}
+
+impl Read for Tls {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let mut incoming = [0u8; 16 * 1024];
+		loop {
+			match self.connection.lock().unwrap().reader().read(buf) {
+				Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+				result => return result,
+			}
+			// Without the lock
+			let n = self.socket.read(&mut incoming)?;
+			let connection = self.connection.clone();
+			let mut connection = connection.lock().unwrap();
+			let mut data = &incoming[..n];
+			loop {
+				// Empty slice tells about EOF
+				connection.read_tls(&mut data)?;
+				connection.process_new_packets().map_err(std::io::Error::other)?;
+				if data.is_empty() {
+					break;
+				}
+			}
+			self.send_queued(&mut connection)?;
+		}
+	}
+}
+
+impl Write for Tls {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let connection = self.connection.clone();
+		let mut connection = connection.lock().unwrap();
+		let n = connection.writer().write(buf)?;
+		self.send_queued(&mut connection)?;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		let connection = self.connection.clone();
+		let mut connection = connection.lock().unwrap();
+		connection.writer().flush()?;
+		self.send_queued(&mut connection)?;
+		self.socket.flush()
+	}
+}
+
+impl Connection {
+	/// Connects to "server:port", "lonelyradio://server:port",
+	/// "lonelyradios://server:port" or "unix:/path"
+	pub(crate) fn open(server: &str) -> anyhow::Result<Self> {
+		if let Some(path) = server.strip_prefix(UNIX_PREFIX) {
+			#[cfg(unix)]
+			return Ok(Self::Unix(
+				UnixStream::connect(path).context("failed to connect to the server")?,
+			));
+			#[cfg(not(unix))]
+			anyhow::bail!("Unix sockets are not supported on this platform: {:?}", path);
+		}
+		if let Some(address) = server.strip_prefix(TLS_SCHEME) {
+			let (host, _) = address.rsplit_once(':').context("port is not specified")?;
+			let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']'))?
+				.to_owned();
+			let config = TLS.read().unwrap().clone();
+			let config = match config {
+				Some(config) => config,
+				None => {
+					set_tls_options(TlsOptions::default())?;
+					TLS.read().unwrap().clone().unwrap()
+				}
+			};
+			let stream = TcpStream::connect(address).context("failed to connect to the server")?;
+			let connection = ClientConnection::new(config, name)?;
+			return Ok(Self::Tls(Tls::connect(stream, connection)?));
+		}
+		let address = server.strip_prefix(SCHEME).unwrap_or(server);
+		Ok(Self::Tcp(TcpStream::connect(address).context("failed to connect to the server")?))
+	}
+
+	/// Another handle to the same connection, used to send control messages
+	pub(crate) fn try_clone(&self) -> Option<Self> {
+		match self {
+			Self::Tcp(s) => s.try_clone().ok().map(Self::Tcp),
+			#[cfg(unix)]
+			Self::Unix(s) => s.try_clone().ok().map(Self::Unix),
+			Self::Tls(s) => s.try_clone().map(Self::Tls),
+		}
+	}
+}
+
+impl Read for Connection {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			Self::Tcp(s) => s.read(buf),
+			#[cfg(unix)]
+			Self::Unix(s) => s.read(buf),
+			Self::Tls(s) => s.read(buf),
+		}
+	}
+}
+
+impl Write for Connection {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			Self::Tcp(s) => s.write(buf),
+			#[cfg(unix)]
+			Self::Unix(s) => s.write(buf),
+			Self::Tls(s) => s.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			Self::Tcp(s) => s.flush(),
+			#[cfg(unix)]
+			Self::Unix(s) => s.flush(),
+			Self::Tls(s) => s.flush(),
+		}
+	}
+}
+
+fn provider() -> Arc<CryptoProvider> {
+	Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Accepts only the certificate with the given SHA-256, for self-signed ones
+#[derive(Debug)]
+struct Pinned {
+	pin: [u8; 32],
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Pinned {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		if Sha256::digest(end_entity).as_slice() == self.pin {
+			Ok(ServerCertVerified::assertion())
+		} else {
+			Err(rustls::Error::InvalidCertificate(
+				rustls::CertificateError::ApplicationVerificationFailure,
+			))
+		}
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+		self.provider.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+	use std::sync::mpsc;
+	use std::time::Duration;
+
+	use rustls::pki_types::PrivateKeyDer;
+	use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+	use super::*;
+
+	#[test]
+	fn control_is_not_held_up_by_reads() {
+		let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+		let config = ServerConfig::builder()
+			.with_no_client_auth()
+			.with_single_cert(
+				vec![certified.cert.der().clone()],
+				PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+			)
+			.unwrap();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap();
+		let server = std::thread::spawn(move || {
+			let connection = ServerConnection::new(Arc::new(config)).unwrap();
+			let mut s = StreamOwned::new(connection, listener.accept().unwrap().0);
+			let mut control = [0u8; 2];
+			s.read_exact(&mut control).unwrap();
+			s.write_all(b"done").unwrap();
+			control
+		});
+
+		set_tls_options(TlsOptions {
+			ca: None,
+			pin: Some(Sha256::digest(certified.cert.der()).into()),
+		})
+		.unwrap();
+		let mut connection = Connection::open(&format!("{}{}", TLS_SCHEME, address)).unwrap();
+		let mut control = connection.try_clone().unwrap();
+		// Blocked until the server answers the control message
+		let reader = std::thread::spawn(move || {
+			let mut reply = [0u8; 4];
+			connection.read_exact(&mut reply).unwrap();
+			reply
+		});
+		std::thread::sleep(Duration::from_millis(100));
+		let (sent, written) = mpsc::channel();
+		std::thread::spawn(move || sent.send(control.write_all(b"sk").is_ok()).unwrap());
+		assert!(written.recv_timeout(Duration::from_secs(5)).unwrap());
+		assert_eq!(&server.join().unwrap(), b"sk");
+		assert_eq!(&reader.join().unwrap(), b"done");
+	}
+}
@@ -1,5 +1,6 @@
 use clap::Parser;
 use monolib::lonelyradio_types::Settings;
+use monolib::TlsOptions;
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -7,23 +8,56 @@ struct Args {
 	/// Remote address
 	address: String,
 
-	#[arg(long)]
-	xor_key_file: Option<PathBuf>,
-
 	#[arg(short, long, default_value = "")]
 	playlist: String,
 
 	#[arg(short, long)]
 	list: bool,
+
+	/// Token for private stations
+	#[arg(long)]
+	token: Option<String>,
+
+	/// Additional trusted CA certificates (PEM) for lonelyradios:// servers
+	#[arg(long)]
+	ca: Option<PathBuf>,
+
+	/// SHA-256 of the server certificate to accept instead of checking CA
+	#[arg(long, value_parser = parse_pin)]
+	pin: Option<[u8; 32]>,
+
+	/// Key file for encrypted stations
+	#[arg(long, alias = "xor-key-file")]
+	key_file: Option<PathBuf>,
+}
+
+fn parse_pin(pin: &str) -> Result<[u8; 32], &'static str> {
+	monolib::parse_pin(pin).ok_or("expected 32 bytes in hex")
 }
 
 fn main() {
 	let args = Args::parse();
+	monolib::set_tls_options(TlsOptions {
+		ca: args.ca.as_ref().map(|x| std::fs::read(x).unwrap()),
+		pin: args.pin,
+	})
+	.unwrap();
+	monolib::set_key(args.key_file.as_ref().map(|x| std::fs::read(x).unwrap()));
+
+	if let Ok(Some(station)) = monolib::get_capabilities(&args.address).map(|x| x.station) {
+		println!("Station: {}", station.name);
+		if !station.description.is_empty() {
+			println!("{}", station.description);
+		}
+		if let Some(homepage) = station.homepage {
+			println!("Homepage: {}", homepage);
+		}
+	}
 
 	if args.list {
 		println!(
 			"Available playlists: {}",
-			match monolib::list_playlists(&args.address) {
+			match monolib::list_playlists(&args.address, args.token.as_deref()) {
 				Some(s) => format!("{:?}", s),
 				None => String::from("None"),
 			}
@@ -36,8 +70,11 @@ fn main() {
 		Settings {
 			encoder: monolib::lonelyradio_types::Encoder::Flac,
 			cover: -1,
+			framing: None,
+			encryption: false,
 		},
 		&args.playlist,
+		args.token.as_deref(),
 	)
 	.unwrap();
 	println!(
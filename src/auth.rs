@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+
+use lonelyradio_types::verify_proof;
+use rand::RngCore;
+
+use crate::config::{Config, Token, CONFIG};
+
+/// What a client is allowed to play
+pub struct Access {
+	config: &'static Config,
+	/// Sent in ServerCapabilities, if there are private stations
+	challenge: Option<Vec<u8>>,
+	proof: Option<Vec<u8>>,
+	/// Address of the client, for `networks` rules
+	ip: Option<IpAddr>,
+}
+
+impl Access {
+	pub fn new(ip: Option<IpAddr>) -> Self {
+		Self::with_config(&CONFIG, ip)
+	}
+
+	fn with_config(config: &'static Config, ip: Option<IpAddr>) -> Self {
+		Self {
+			config,
+			challenge: (!config.auth.is_empty()).then(|| {
+				let mut challenge = vec![0u8; 32];
+				rand::thread_rng().fill_bytes(&mut challenge);
+				challenge
+			}),
+			proof: None,
+			ip,
+		}
+	}
+
+	pub fn challenge(&self) -> Option<Vec<u8>> {
+		self.challenge.clone()
+	}
+
+	/// Remembers the proof, if it matches any valid token
+	pub fn authenticate(&mut self, proof: Vec<u8>) -> bool {
+		if self.config.auth.values().flatten().any(|x| self.matches(x, &proof)) {
+			self.proof = Some(proof);
+			return true;
+		}
+		false
+	}
+
+	/// `""` is the whole library (and everything from Browse and Search)
+	pub fn allows(&self, station: &str) -> bool {
+		let tokens: Vec<&Token> =
+			[station, "*"].iter().filter_map(|x| self.config.auth.get(*x)).flatten().collect();
+		self.config.network_allows(station, self.ip)
+			&& (tokens.is_empty()
+				|| self.proof.as_ref().is_some_and(|p| tokens.iter().any(|x| self.matches(x, p))))
+	}
+
+	fn matches(&self, token: &Token, proof: &[u8]) -> bool {
+		!token.expired()
+			&& self.challenge.as_ref().is_some_and(|x| verify_proof(&token.token, x, proof))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use lonelyradio_types::auth_proof;
+
+	use super::*;
+
+	fn config(toml: &str) -> &'static Config {
+		Box::leak(Box::new(toml::from_str(toml).unwrap()))
+	}
+
+	fn login(config: &'static Config, token: &str) -> (Access, bool) {
+		let mut access = Access::with_config(config, None);
+		let proof = auth_proof(token, &access.challenge().unwrap());
+		let authenticated = access.authenticate(proof);
+		(access, authenticated)
+	}
+
+	#[test]
+	fn public() {
+		let access = Access::with_config(config(""), None);
+		assert_eq!(access.challenge(), None);
+		assert!(access.allows("") && access.allows("jazz"));
+	}
+
+	#[test]
+	fn private_stations() {
+		let config = config(
+			"[[auth.jazz]]\ntoken = \"jazz token\"\n[[auth.\"\"]]\ntoken = \"library token\"",
+		);
+		let access = Access::with_config(config, None);
+		assert_eq!(access.challenge().unwrap().len(), 32);
+		assert!(!access.allows("jazz") && !access.allows(""));
+		assert!(access.allows("rock"));
+
+		let (access, authenticated) = login(config, "jazz token");
+		assert!(authenticated);
+		assert!(access.allows("jazz") && !access.allows(""));
+		let (access, authenticated) = login(config, "wrong");
+		assert!(!authenticated);
+		assert!(!access.allows("jazz"));
+
+		// Proof is only good for its own challenge
+		let mut access = Access::with_config(config, None);
+		assert!(!access.authenticate(auth_proof("jazz token", &[0; 32])));
+	}
+
+	#[test]
+	fn every_station() {
+		let config = config("[[auth.\"*\"]]\ntoken = \"a\"\n[[auth.\"*\"]]\ntoken = \"b\"");
+		assert!(!Access::with_config(config, None).allows("jazz"));
+		for token in ["a", "b"] {
+			let (access, authenticated) = login(config, token);
+			assert!(authenticated && access.allows("jazz") && access.allows(""));
+		}
+	}
+
+	#[test]
+	fn networks() {
+		let config = config(
+			"[networks.\"*\"]\ndeny = [\"203.0.113.0/24\"]\n[networks.jazz]\nallow = [\"10.0.0.0/8\", 			 \"::1\"]\ndeny = [\"10.0.0.13\"]",
+		);
+		let access = |ip: &str| Access::with_config(config, Some(ip.parse().unwrap()));
+		assert!(access("10.1.2.3").allows("jazz") && access("10.1.2.3").allows(""));
+		assert!(access("::1").allows("jazz"));
+		assert!(!access("10.0.0.13").allows("jazz") && access("10.0.0.13").allows(""));
+		assert!(!access("192.168.1.1").allows("jazz") && access("192.168.1.1").allows("rock"));
+		// Rules for every station apply to each one
+		assert!(!access("203.0.113.5").allows("") && !access("203.0.113.5").allows("rock"));
+		assert!(!config.network_allows("*", Some("203.0.113.5".parse().unwrap())));
+		// Unix socket clients have no address to check
+		assert!(Access::with_config(config, None).allows("jazz"));
+	}
+
+	#[test]
+	fn expired_token() {
+		let config = config("[[auth.jazz]]\ntoken = \"old\"\nexpires = 2020-01-01");
+		let (access, authenticated) = login(config, "old");
+		assert!(!authenticated && !access.allows("jazz"));
+	}
+}
@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+/// Network like "192.168.1.0/24" or "2001:db8::/32", or a single address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+	address: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		// IPv4 clients of dual-stack sockets are IPv4-mapped IPv6 addresses
+		match (self.address, ip.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (address, prefix) = match s.split_once('/') {
+			Some((address, prefix)) => (address, Some(prefix)),
+			None => (s, None),
+		};
+		let address: IpAddr =
+			address.parse().map_err(|_| format!("invalid network address \"{}\"", s))?;
+		let max = if address.is_ipv4() {
+			32
+		} else {
+			128
+		};
+		let prefix = match prefix {
+			None => max,
+			Some(prefix) => prefix
+				.parse()
+				.ok()
+				.filter(|x| *x <= max)
+				.ok_or_else(|| format!("invalid prefix length in \"{}\"", s))?,
+		};
+		Ok(Self {
+			address,
+			prefix,
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+	fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+		String::deserialize(d)?.parse().map_err(D::Error::custom)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn contains(network: &str, ip: &str) -> bool {
+		network.parse::<Cidr>().unwrap().contains(ip.parse().unwrap())
+	}
+
+	#[test]
+	fn prefixes() {
+		assert!(contains("192.168.1.0/24", "192.168.1.255"));
+		assert!(!contains("192.168.1.0/24", "192.168.2.1"));
+		assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+		assert!(!contains("2001:db8::/32", "2001:db9::1"));
+		// No prefix is a single address
+		assert!(contains("10.0.0.1", "10.0.0.1"));
+		assert!(!contains("10.0.0.1", "10.0.0.2"));
+	}
+
+	#[test]
+	fn whole_and_single() {
+		assert!(contains("0.0.0.0/0", "203.0.113.7"));
+		assert!(contains("::/0", "2001:db8::1"));
+		assert!(contains("10.0.0.1/32", "10.0.0.1"));
+		assert!(!contains("10.0.0.1/32", "10.0.0.0"));
+		assert!(contains("2001:db8::1/128", "2001:db8::1"));
+		assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+		// Families never match each other
+		assert!(!contains("::/0", "203.0.113.7"));
+		assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+	}
+
+	#[test]
+	fn ipv4_mapped() {
+		assert!(contains("192.168.0.0/16", "::ffff:192.168.1.1"));
+		assert!(!contains("192.168.0.0/16", "::ffff:10.0.0.1"));
+		assert!(!contains("::/0", "::ffff:192.168.1.1"));
+	}
+
+	#[test]
+	fn invalid() {
+		for network in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/x", "example.com"] {
+			assert!(network.parse::<Cidr>().is_err(), "{}", network);
+		}
+	}
+}
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use clap::Parser;
+use image::ImageFormat;
+use lonelyradio_types::StationInfo;
+use once_cell::sync::Lazy;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use tracing::warn;
+
+use crate::cidr::Cidr;
+use crate::Args;
+
+/// Server configuration, read from `--config` (TOML).
+/// Everything here is optional, defaults are used without `--config`
+pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+	// Tests have no arguments to parse
+	if cfg!(test) {
+		return Config::default();
+	}
+	match Args::parse().config {
+		None => Config::default(),
+		Some(path) => Config::load(&path),
+	}
+});
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// Playlist name -> tag query (see `query.rs`)
+	pub smart_playlists: HashMap<String, String>,
+	/// Sent to clients in ServerCapabilities
+	pub station: Option<Station>,
+	/// Station ("" for the whole library, "*" for every one) -> tokens, which
+	/// give access to it. Stations without tokens are public
+	pub auth: HashMap<String, Vec<Token>>,
+	/// Station ("" for the whole library, "*" for every one) -> key file.
+	/// Streams of these stations are encrypted
+	pub keys: HashMap<String, PathBuf>,
+	/// Station ("" for the whole library, "*" for every one) -> networks
+	/// allowed to or denied from playing it. Rules for "*" are checked on
+	/// connection
+	pub networks: HashMap<String, Networks>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+	pub token: String,
+	/// TOML date or date-time, local time is used if offset is not specified.
+	/// Tokens with a date are valid until the end of that day
+	#[serde(default, deserialize_with = "deserialize_expiry")]
+	pub expires: Option<DateTime<Local>>,
+}
+
+impl Token {
+	pub fn expired(&self) -> bool {
+		self.expires.is_some_and(|x| x <= Local::now())
+	}
+}
+
+fn deserialize_expiry<'de, D: Deserializer<'de>>(
+	d: D,
+) -> Result<Option<DateTime<Local>>, D::Error> {
+	let Some(expires) = Option::<toml::value::Datetime>::deserialize(d)?.map(|x| x.to_string())
+	else {
+		return Ok(None);
+	};
+	if let Ok(x) = DateTime::parse_from_rfc3339(&expires) {
+		return Ok(Some(x.with_timezone(&Local)));
+	}
+	NaiveDateTime::parse_from_str(&expires, "%Y-%m-%dT%H:%M:%S%.f")
+		.ok()
+		.or_else(|| {
+			// Expires at the start of the next day
+			let date = NaiveDate::parse_from_str(&expires, "%Y-%m-%d").ok()?;
+			Some(date.succ_opt()?.into())
+		})
+		.and_then(|x| x.and_local_timezone(Local).earliest())
+		.map(Some)
+		.ok_or_else(|| D::Error::custom(format!("invalid expiry date \"{}\"", expires)))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Station {
+	pub name: String,
+	#[serde(default)]
+	pub description: String,
+	pub homepage: Option<String>,
+	/// Logo (PNG, JPEG or BMP), scaled down to ICON_SIZE
+	pub icon: Option<PathBuf>,
+}
+
+const ICON_SIZE: u32 = 256;
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Networks {
+	/// If not empty, only these are allowed
+	pub allow: Vec<Cidr>,
+	pub deny: Vec<Cidr>,
+}
+
+impl Networks {
+	fn allows(&self, ip: IpAddr) -> bool {
+		!self.deny.iter().any(|x| x.contains(ip))
+			&& (self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip)))
+	}
+}
+
+/// Whether the client may play the station according to `networks`. Clients
+/// without an address (on Unix sockets) always may
+pub fn network_allows(station: &str, ip: Option<IpAddr>) -> bool {
+	CONFIG.network_allows(station, ip)
+}
+
+/// Contents of key files from `keys`
+pub static KEYS: Lazy<HashMap<String, Vec<u8>>> = Lazy::new(|| {
+	CONFIG
+		.keys
+		.iter()
+		.map(|(station, path)| {
+			let key = std::fs::read(path)
+				.unwrap_or_else(|e| panic!("failed to read key {:?}: {}", path, e));
+			(station.clone(), key)
+		})
+		.collect()
+});
+
+/// Key of the station, stations without one are not encrypted
+pub fn station_key(station: &str) -> Option<&'static [u8]> {
+	KEYS.get(station).or_else(|| KEYS.get("*")).map(|x| x.as_slice())
+}
+
+/// Station info with the icon already converted
+pub static STATION: Lazy<Option<StationInfo>> =
+	Lazy::new(|| CONFIG.station.as_ref().map(Station::info));
+
+impl Station {
+	fn info(&self) -> StationInfo {
+		StationInfo {
+			name: self.name.clone(),
+			description: self.description.clone(),
+			homepage: self.homepage.clone(),
+			icon: self.icon.as_deref().and_then(load_icon),
+		}
+	}
+}
+
+fn load_icon(path: &Path) -> Option<Vec<u8>> {
+	let icon = match image::open(path) {
+		Ok(i) => i,
+		Err(e) => {
+			warn!("Skipping station icon {:?}: {}", path, e);
+			return None;
+		}
+	};
+	let mut buf = Cursor::new(Vec::new());
+	icon.thumbnail(ICON_SIZE, ICON_SIZE).to_rgb8().write_to(&mut buf, ImageFormat::Jpeg).ok()?;
+	Some(buf.into_inner())
+}
+
+impl Config {
+	fn load(path: &Path) -> Self {
+		let content = std::fs::read_to_string(path)
+			.unwrap_or_else(|e| panic!("failed to read config {:?}: {}", path, e));
+		toml::from_str(&content)
+			.unwrap_or_else(|e| panic!("failed to parse config {:?}: {}", path, e))
+	}
+
+	pub fn network_allows(&self, station: &str, ip: Option<IpAddr>) -> bool {
+		let Some(ip) = ip else {
+			return true;
+		};
+		[station, "*"].iter().filter_map(|x| self.networks.get(*x)).all(|x| x.allows(ip))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::Duration;
+
+	use super::*;
+
+	fn expires(value: &str) -> DateTime<Local> {
+		toml::from_str::<Token>(&format!("token = \"t\"\nexpires = {}", value))
+			.unwrap()
+			.expires
+			.unwrap()
+	}
+
+	fn local(date: NaiveDate, h: u32, m: u32, s: u32) -> DateTime<Local> {
+		date.and_hms_opt(h, m, s).unwrap().and_local_timezone(Local).earliest().unwrap()
+	}
+
+	#[test]
+	fn date_lasts_until_end_of_day() {
+		let date = NaiveDate::from_ymd_opt(2026, 12, 31).unwrap();
+		assert_eq!(expires("2026-12-31"), local(date.succ_opt().unwrap(), 0, 0, 0));
+		assert!(expires("2026-12-31") > local(date, 23, 59, 59));
+	}
+
+	#[test]
+	fn date_time() {
+		let date = NaiveDate::from_ymd_opt(2026, 12, 31).unwrap();
+		assert_eq!(expires("2026-12-31T12:30:00"), local(date, 12, 30, 0));
+		assert_eq!(
+			expires("2026-12-31T12:30:00Z"),
+			date.and_hms_opt(12, 30, 0).unwrap().and_utc().with_timezone(&Local)
+		);
+	}
+
+	#[test]
+	fn station_info() {
+		let icon =
+			std::env::temp_dir().join(format!("lonelyradio-icon-{}.png", std::process::id()));
+		image::RgbImage::new(1024, 512).save(&icon).unwrap();
+		let station: Station = toml::from_str(&format!(
+			"name = \"Night\"\nhomepage = \"https://example.com\"\nicon = {:?}",
+			icon
+		))
+		.unwrap();
+		let info = station.info();
+		std::fs::remove_file(&icon).unwrap();
+		assert_eq!(info.name, "Night");
+		assert_eq!(info.description, "");
+		assert_eq!(info.homepage.as_deref(), Some("https://example.com"));
+		// Scaled down to fit, as JPEG
+		let icon = info.icon.unwrap();
+		assert_eq!(image::guess_format(&icon).unwrap(), ImageFormat::Jpeg);
+		let icon = image::load_from_memory(&icon).unwrap();
+		assert_eq!((icon.width(), icon.height()), (ICON_SIZE, ICON_SIZE / 2));
+	}
+
+	#[test]
+	fn broken_icon_is_skipped() {
+		let station: Station =
+			toml::from_str("name = \"Night\"\nicon = \"/nonexistent.png\"").unwrap();
+		let info = station.info();
+		assert_eq!(info.icon, None);
+		assert!(toml::from_str::<Station>("name = \"Night\"\nlogo = \"x.png\"").is_err());
+	}
+
+	#[test]
+	fn expired() {
+		let token = |expires| Token {
+			token: String::new(),
+			expires,
+		};
+		assert!(!token(None).expired());
+		assert!(token(Some(Local::now() - Duration::seconds(1))).expired());
+		assert!(!token(Some(Local::now() + Duration::hours(1))).expired());
+		let today = Local::now().date_naive().format("%Y-%m-%d").to_string();
+		assert!(!token(Some(expires(&today))).expired());
+	}
+}
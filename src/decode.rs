@@ -9,7 +9,7 @@ use symphonia::core::io::MediaSourceStream;
 use symphonia::core::probe::Hint;
 use symphonia::core::units::Time;
 
-use crate::Args;
+use crate::{metrics, Args};
 
 pub fn get_meta(file_path: &Path, encoder_wants: u32) -> (u16, u32, Time) {
 	let file = Box::new(std::fs::File::open(file_path).unwrap());
@@ -148,6 +148,7 @@ pub fn decode_file_stream(file_path: PathBuf, encoder_wants: u32) -> impl Stream
 				}
 				_ => {
 					// Handling any error as track skip
+					metrics::decode_failed();
 					continue;
 				}
 			}
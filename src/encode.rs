@@ -1,13 +1,22 @@
+#[cfg(feature = "vorbis")]
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
 use lonelyradio_types::Encoder;
 
-// Return: 0 - encoded bytes, 1 - magic cookie (for alac only)
+// 0 - encoded bytes, 1 - magic cookie (for alac only)
+pub type Encoded = (Vec<u8>, Option<Vec<u8>>);
+
+// Encoded samples and their number
+pub type Fragment = (Encoded, usize);
+
 #[allow(unused_variables)]
 pub fn encode(
 	codec: Encoder,
 	mut samples: Vec<f32>,
 	sample_rate: u32,
 	channels: u16,
-) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+) -> Option<Encoded> {
 	match codec {
 		Encoder::Pcm16 => {
 			#[allow(unused_mut)]
@@ -156,3 +165,229 @@ pub fn encode(
 		Encoder::Aac | Encoder::Opus | Encoder::WavPack => unimplemented!(),
 	}
 }
+
+/// Like `encode()`, but splits samples so every encoded fragment fits into
+/// `max` bytes. Parts are sized by the expected encoded size up front, halved
+/// again only if that was too optimistic
+pub fn encode_bounded(
+	codec: Encoder,
+	samples: Vec<f32>,
+	sample_rate: u32,
+	channels: u16,
+	max: Option<usize>,
+) -> Option<Vec<Fragment>> {
+	let length = samples.len();
+	let frame = channels as usize;
+	let Some(max) = max.filter(|_| length >= frame * 2) else {
+		return Some(vec![(encode(codec, samples, sample_rate, channels)?, length)]);
+	};
+	let frames = length / frame;
+	let parts = estimate(codec, length, sample_rate, channels).div_ceil(max).clamp(1, frames);
+	let mut fragments = vec![];
+	for part in samples.chunks(frames.div_ceil(parts) * frame) {
+		let encoded = encode(codec, part.to_vec(), sample_rate, channels)?;
+		if encoded.0.len() <= max || part.len() < frame * 2 {
+			fragments.push((encoded, part.len()));
+			continue;
+		}
+		let (first, second) = part.split_at(part.len() / frame / 2 * frame);
+		fragments.extend(encode_bounded(codec, first.to_vec(), sample_rate, channels, Some(max))?);
+		fragments.extend(encode_bounded(codec, second.to_vec(), sample_rate, channels, Some(max))?);
+	}
+	Some(fragments)
+}
+
+/// Expected size of `length` encoded samples in bytes, exact for PCM
+fn estimate(codec: Encoder, length: usize, sample_rate: u32, channels: u16) -> usize {
+	let bytes_per_sample = match codec {
+		Encoder::Pcm16 => 2.0,
+		Encoder::PcmFloat => 4.0,
+		// Lossless, rarely larger than 16-bit PCM
+		Encoder::Flac | Encoder::Alac => 2.0,
+		// 5 bits of residuals and headers
+		Encoder::Sea => 0.75,
+		// Bitrate is capped at 192 kbit/s
+		Encoder::Vorbis => 192.0 * 1024.0 / 8.0 / (sample_rate as f64 * channels as f64),
+		Encoder::Aac | Encoder::Opus | Encoder::WavPack => unimplemented!(),
+	};
+	(length as f64 * bytes_per_sample).ceil() as usize
+}
+
+/// Vorbis encoder producing one continuous Ogg stream, for players that can't
+/// handle chained streams. The format is fixed by the first track, samples of
+/// later ones are converted to it
+pub struct OggVorbis {
+	sample_rate: u32,
+	channels: u16,
+	/// Converter from the current track's sample rate. It's kept between
+	/// chunks, so its filter state carries over and chunk edges don't click
+	resampler: Option<(u32, samplerate::Samplerate)>,
+	#[cfg(feature = "vorbis")]
+	encoder: vorbis_rs::VorbisEncoder<Shared>,
+	/// Ogg pages written by the encoder, not yet taken
+	output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl OggVorbis {
+	pub fn new(sample_rate: u32, channels: u16) -> Self {
+		let output = Arc::new(Mutex::new(vec![]));
+		Self {
+			sample_rate,
+			channels,
+			resampler: None,
+			#[cfg(feature = "vorbis")]
+			encoder: {
+				use std::num::{NonZeroU32, NonZeroU8};
+				vorbis_rs::VorbisEncoderBuilder::new(
+					NonZeroU32::new(sample_rate).unwrap(),
+					NonZeroU8::new(channels as u8).unwrap(),
+					Shared(output.clone()),
+				)
+				.unwrap()
+				.bitrate_management_strategy(
+					vorbis_rs::VorbisBitrateManagementStrategy::ConstrainedAbr {
+						maximum_bitrate: NonZeroU32::new(192 * 1024).unwrap(),
+					},
+				)
+				.build()
+				.unwrap()
+			},
+			output,
+		}
+	}
+
+	/// Encodes interleaved samples, returns Ogg pages completed so far
+	#[allow(unused_variables, unused_assignments)]
+	pub fn encode(&mut self, samples: Vec<f32>, sample_rate: u32, channels: u16) -> Vec<u8> {
+		let mut samples = convert(samples, channels, self.channels);
+		if sample_rate != self.sample_rate {
+			if !matches!(self.resampler, Some((rate, _)) if rate == sample_rate) {
+				let resampler = samplerate::Samplerate::new(
+					samplerate::ConverterType::Linear,
+					sample_rate,
+					self.sample_rate,
+					self.channels as usize,
+				)
+				.unwrap();
+				self.resampler = Some((sample_rate, resampler));
+			}
+			samples = self.resampler.as_ref().unwrap().1.process(&samples).unwrap();
+		}
+		#[cfg(feature = "vorbis")]
+		{
+			let channels = self.channels as usize;
+			let blocks: Vec<Vec<f32>> = (0..channels)
+				.map(|i| samples[i..].iter().step_by(channels).copied().collect())
+				.collect();
+			self.encoder.encode_audio_block(blocks).unwrap();
+		}
+		std::mem::take(&mut *self.output.lock().unwrap())
+	}
+}
+
+/// Sink shared with the encoder, so pages could be taken while it's alive
+#[cfg(feature = "vorbis")]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(feature = "vorbis")]
+impl Write for Shared {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Remaps interleaved samples to another channel count. Extra channels are
+/// mixed down, missing ones are copied
+fn convert(samples: Vec<f32>, from: u16, to: u16) -> Vec<f32> {
+	let (input, output) = (from as usize, to as usize);
+	if input == output {
+		samples
+	} else {
+		samples
+			.chunks_exact(input)
+			.flat_map(|frame| {
+				(0..output).map(move |c| {
+					if input < output {
+						return frame[c % input];
+					}
+					let mixed: Vec<f32> = frame.iter().skip(c).step_by(output).copied().collect();
+					mixed.iter().sum::<f32>() / mixed.len() as f32
+				})
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn samples(n: usize) -> Vec<f32> {
+		(0..n).map(|x| x as f32 / n as f32).collect()
+	}
+
+	#[test]
+	fn unbounded() {
+		let fragments = encode_bounded(Encoder::Pcm16, samples(1000), 44100, 2, None).unwrap();
+		assert_eq!(fragments.len(), 1);
+		assert_eq!(fragments[0].1, 1000);
+		assert_eq!(fragments[0].0 .0.len(), 2000);
+	}
+
+	#[test]
+	fn split_to_fit() {
+		let input = samples(1000);
+		let fragments = encode_bounded(Encoder::Pcm16, input.clone(), 44100, 2, Some(600)).unwrap();
+		assert!(fragments.len() > 1);
+		assert!(fragments.iter().all(|((bytes, _), _)| bytes.len() <= 600));
+		// Split on frame boundaries, nothing lost or reordered
+		assert!(fragments.iter().all(|(_, length)| length % 2 == 0));
+		assert_eq!(fragments.iter().map(|x| x.1).sum::<usize>(), 1000);
+		let joined: Vec<u8> = fragments.into_iter().flat_map(|((bytes, _), _)| bytes).collect();
+		assert_eq!(joined, encode(Encoder::Pcm16, input, 44100, 2).unwrap().0);
+	}
+
+	#[test]
+	fn split_by_estimate() {
+		// 2000 bytes: three parts fit, halving would make four
+		let fragments = encode_bounded(Encoder::Pcm16, samples(1000), 44100, 2, Some(700)).unwrap();
+		let lengths: Vec<usize> = fragments.iter().map(|x| x.1).collect();
+		assert_eq!(lengths, [334, 334, 332]);
+		assert!(fragments.iter().all(|((bytes, _), _)| bytes.len() <= 700));
+	}
+
+	#[test]
+	fn uneven_parts() {
+		let fragments = encode_bounded(Encoder::Pcm16, samples(7), 44100, 1, Some(4)).unwrap();
+		let lengths: Vec<usize> = fragments.iter().map(|x| x.1).collect();
+		assert_eq!(lengths, [2, 2, 2, 1]);
+		assert!(fragments.iter().all(|((bytes, _), _)| bytes.len() <= 4));
+	}
+
+	#[test]
+	fn mixdown() {
+		// Channels are mixed round-robin: 1+3 and 2+4
+		let samples = convert(vec![0.25, 0.5, 0.75, 1.0, 1.0, 0.0, 1.0, 0.0], 4, 2);
+		assert_eq!(samples, [0.5, 0.75, 1.0, 0.0]);
+		assert_eq!(convert(vec![0.25, 0.75, -1.0, 1.0], 2, 1), [0.5, 0.0]);
+	}
+
+	#[test]
+	fn missing_channels_are_copied() {
+		assert_eq!(convert(vec![0.1, 0.2], 1, 2), [0.1, 0.1, 0.2, 0.2]);
+		assert_eq!(convert(vec![0.1, 0.2, 0.3, 0.4], 2, 3), [0.1, 0.2, 0.1, 0.3, 0.4, 0.3]);
+		assert_eq!(convert(vec![0.1, 0.2], 2, 2), [0.1, 0.2]);
+	}
+
+	#[test]
+	fn single_frame_is_not_split() {
+		let fragments = encode_bounded(Encoder::PcmFloat, samples(2), 44100, 2, Some(1)).unwrap();
+		assert_eq!(fragments.len(), 1);
+		assert_eq!(fragments[0].1, 2);
+	}
+}
@@ -0,0 +1,291 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use futures_util::{pin_mut, StreamExt};
+use lonelyradio_types::{Encoder, Settings};
+use percent_encoding::percent_decode_str;
+use tracing::{info, warn};
+
+use crate::auth::Access;
+use crate::config::{station_key, STATION};
+use crate::decode::{decode_file_stream, get_meta};
+use crate::encode::OggVorbis;
+use crate::library;
+use crate::limits::Slot;
+use crate::log::Session;
+use crate::metrics;
+use crate::shutdown;
+use crate::source::{Catalog, TrackSource};
+use crate::status;
+use crate::transport::Transport;
+use crate::web::peek_head;
+use crate::{get_metadata, Args, Metadata, Pacing};
+
+/// Bytes of audio between ICY metadata blocks
+const METAINT: usize = 16000;
+
+/// Samples encoded at once
+const CHUNK_SECONDS: usize = 1;
+
+/// Players fill their buffers this far ahead
+const LEAD: Duration = Duration::from_secs(10);
+
+/// Players that stopped reading for this long are dropped
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Streams a station as endless Ogg Vorbis to an Icecast/SHOUTcast client.
+/// "/" is the whole library, "/name" is a playlist. Private and encrypted
+/// stations aren't served. Blocks, so it's run with spawn_blocking
+pub fn serve(mut s: TcpStream, _slot: Slot, catalog: Arc<Catalog>, mut session: Session) {
+	let _entered = session.span().clone().entered();
+	let Some((head, length)) = peek_head(&s) else {
+		return;
+	};
+	if s.read_exact(&mut vec![0u8; length]).is_err() {
+		return;
+	}
+	let mut lines = head.lines();
+	let station = station(lines.next().and_then(|x| x.split_whitespace().nth(1)).unwrap_or("/"));
+	let metadata = lines.any(|x| {
+		x.split_once(':')
+			.is_some_and(|(k, v)| k.trim().eq_ignore_ascii_case("icy-metadata") && v.trim() == "1")
+	});
+
+	let Some(tracklist) = catalog.playlist(&station) else {
+		let _ = s.write_all(b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+		return;
+	};
+	if !Access::new(s.peer()).allows(&station) || station_key(&station).is_some() {
+		warn!(station, "rejected");
+		metrics::rejected("unauthorized");
+		let _ = s.write_all(b"HTTP/1.0 403 Forbidden\r\nContent-Length: 0\r\n\r\n");
+		return;
+	}
+
+	let mut response = String::from(
+		"HTTP/1.0 200 OK\r\nContent-Type: application/ogg\r\nCache-Control: no-cache\r\nicy-pub: \
+		 0\r\n",
+	);
+	let name = match (STATION.as_ref(), station.as_str()) {
+		(Some(info), "") => info.name.clone(),
+		(Some(info), playlist) => format!("{} - {}", info.name, playlist),
+		(None, "") => "lonelyradio".to_string(),
+		(None, playlist) => playlist.to_string(),
+	};
+	response += &format!("icy-name: {}\r\n", name);
+	if let Some(info) = STATION.as_ref() {
+		if !info.description.is_empty() {
+			response += &format!("icy-description: {}\r\n", info.description);
+		}
+		if let Some(homepage) = info.homepage.as_ref() {
+			response += &format!("icy-url: {}\r\n", homepage);
+		}
+	}
+	if metadata {
+		response += &format!("icy-metaint: {}\r\n", METAINT);
+	}
+	response += "\r\n";
+	if s.set_write_timeout(Some(STALL_TIMEOUT)).is_err()
+		|| s.write_all(response.as_bytes()).is_err()
+	{
+		return;
+	}
+
+	let args = Args::parse();
+	let settings = Settings {
+		encoder: Encoder::Vorbis,
+		cover: -1,
+		framing: None,
+		encryption: false,
+	};
+	let mut icy = Icy {
+		interval: metadata.then_some(METAINT),
+		left: METAINT,
+		title: None,
+	};
+	let runtime = tokio::runtime::Handle::current();
+	let mut pacing = Pacing::new(LEAD);
+	let mut vorbis: Option<OggVorbis> = None;
+	let mut source = TrackSource::shuffle(tracklist);
+	let _running = shutdown::Running::new();
+	let _listening = metrics::Listening::new(&station, Encoder::Vorbis);
+	while let Some(track) = source.next() {
+		if shutdown::stopping() {
+			return;
+		}
+		let Some(Metadata {
+			title,
+			album,
+			artist,
+			..
+		}) = get_metadata(&track, &args, &settings)
+		else {
+			continue;
+		};
+		session.tracks += 1;
+		metrics::played(&station);
+		status::played(&station, library::track_id(&args.dir, &track), &artist, &album, &title);
+		info!(%artist, %album, %title, encoder = ?Encoder::Vorbis, "playing");
+		icy.title = Some(if artist.is_empty() {
+			title
+		} else {
+			format!("{} - {}", artist, title)
+		});
+
+		let (channels, sample_rate, _) = get_meta(track.as_path(), 48000);
+		let chunk = CHUNK_SECONDS * sample_rate as usize * channels as usize;
+		let vorbis = vorbis.get_or_insert_with(|| OggVorbis::new(sample_rate, channels));
+		let stream = decode_file_stream(track.clone(), 48000);
+		pin_mut!(stream);
+		let mut samples = vec![];
+		loop {
+			let next = runtime.block_on(stream.next());
+			if let Some(decoded) = next.as_ref() {
+				samples.extend_from_slice(decoded);
+				if samples.len() < chunk {
+					continue;
+				}
+			}
+			if !samples.is_empty() {
+				let duration = Duration::from_secs_f64(
+					samples.len() as f64 / channels as f64 / sample_rate as f64,
+				);
+				let start = Instant::now();
+				let encoded = vorbis.encode(std::mem::take(&mut samples), sample_rate, channels);
+				metrics::encoded(Encoder::Vorbis, start.elapsed(), 1);
+				session.bytes += encoded.len() as u64;
+				metrics::bytes_sent(encoded.len());
+				if icy.write(&mut s, &encoded).is_err() {
+					return;
+				}
+				runtime.block_on(pacing.pace(duration));
+				if shutdown::expired() {
+					return;
+				}
+			}
+			if next.is_none() {
+				break;
+			}
+		}
+	}
+}
+
+/// Playlist name from a request target, without the query and fragment
+fn station(target: &str) -> String {
+	let path = target.split(['?', '#']).next().unwrap_or_default();
+	percent_decode_str(path.trim_start_matches('/')).decode_utf8_lossy().to_string()
+}
+
+/// Interleaves ICY metadata into the stream
+struct Icy {
+	/// Bytes between metadata blocks, if the client asked for them
+	interval: Option<usize>,
+	/// Bytes until the next block
+	left: usize,
+	/// Title to send in the next block
+	title: Option<String>,
+}
+
+impl Icy {
+	fn write(&mut self, s: &mut impl Write, mut data: &[u8]) -> std::io::Result<()> {
+		let Some(interval) = self.interval else {
+			return s.write_all(data);
+		};
+		while !data.is_empty() {
+			let n = self.left.min(data.len());
+			s.write_all(&data[..n])?;
+			data = &data[n..];
+			self.left -= n;
+			if self.left == 0 {
+				s.write_all(&self.block())?;
+				self.left = interval;
+			}
+		}
+		Ok(())
+	}
+
+	/// Length in 16-byte units and padded "StreamTitle='...';", or just 0 if
+	/// the title hasn't changed
+	fn block(&mut self) -> Vec<u8> {
+		let Some(title) = self.title.take() else {
+			return vec![0];
+		};
+		// Block is at most 255 * 16 bytes
+		let title: String = title.replace('\'', "’").chars().take(1000).collect();
+		let mut block = format!("StreamTitle='{}';", title).into_bytes();
+		let length = block.len().div_ceil(16);
+		block.resize(length * 16, 0);
+		block.insert(0, length as u8);
+		block
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stations() {
+		assert_eq!(station("/"), "");
+		assert_eq!(station("/jazz"), "jazz");
+		assert_eq!(station("/jazz?foo=1"), "jazz");
+		assert_eq!(station("/jazz#top"), "jazz");
+		assert_eq!(station("/?type=.mp3"), "");
+		assert_eq!(station("/late%20night?x=%3F"), "late night");
+	}
+
+	#[test]
+	fn metadata_every_interval() {
+		let mut icy = Icy {
+			interval: Some(4),
+			left: 4,
+			title: Some("a".to_string()),
+		};
+		let mut out = vec![];
+		icy.write(&mut out, &[1, 2, 3]).unwrap();
+		icy.write(&mut out, &[4, 5, 6, 7, 8, 9]).unwrap();
+		let title = b"StreamTitle='a';";
+		let mut expected = vec![1, 2, 3, 4, 1];
+		expected.extend_from_slice(title);
+		// Unchanged title is an empty block
+		expected.extend_from_slice(&[5, 6, 7, 8, 0, 9]);
+		assert_eq!(out, expected);
+		assert_eq!(icy.left, 3);
+	}
+
+	#[test]
+	fn without_metadata() {
+		let mut icy = Icy {
+			interval: None,
+			left: 4,
+			title: Some("a".to_string()),
+		};
+		let mut out = vec![];
+		icy.write(&mut out, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).unwrap();
+		assert_eq!(out, [1, 2, 3, 4, 5, 6, 7, 8, 9]);
+	}
+
+	#[test]
+	fn blocks_are_padded() {
+		let mut icy = Icy {
+			interval: Some(4),
+			left: 4,
+			title: Some("Artist - It's".to_string()),
+		};
+		let block = icy.block();
+		let text = "StreamTitle='Artist - It’s';";
+		assert_eq!(block[0] as usize, text.len().div_ceil(16));
+		assert_eq!(block.len(), 1 + block[0] as usize * 16);
+		assert_eq!(&block[1..=text.len()], text.as_bytes());
+		assert!(block[1 + text.len()..].iter().all(|x| *x == 0));
+		assert_eq!(icy.block(), [0]);
+
+		// Long titles are cut, so the length byte doesn't wrap
+		icy.title = Some("x".repeat(5000));
+		let block = icy.block();
+		assert_eq!(block.len(), 1 + block[0] as usize * 16);
+	}
+}
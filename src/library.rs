@@ -0,0 +1,313 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use lofty::{Accessor, TaggedFileExt};
+use lonelyradio_types::{Browse, LibraryItem, LibraryResponce, LibraryTrack, Page};
+
+// Maximum number of items in one response
+const MAX_PAGE: u32 = 500;
+
+/// Track tags, read once at scan time
+#[derive(Clone, Debug, Default)]
+pub struct TrackInfo {
+	pub id: u64,
+	pub path: PathBuf,
+	pub title: String,
+	pub artist: String,
+	pub album: String,
+	pub genre: String,
+	pub year: Option<u32>,
+	pub track_number: Option<u32>,
+}
+
+impl TrackInfo {
+	pub fn read(root: &Path, path: &Path) -> Self {
+		let mut info = TrackInfo {
+			id: track_id(root, path),
+			path: path.to_path_buf(),
+			title: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+			..Default::default()
+		};
+		let Ok(tagged) = lofty::read_from_path(path) else {
+			return info;
+		};
+		if let Some(tag) = tagged.primary_tag().or(tagged.first_tag()) {
+			if let Some(title) = tag.title() {
+				info.title = title.to_string();
+			}
+			info.artist = tag.artist().unwrap_or_default().to_string();
+			info.album = tag.album().unwrap_or_default().to_string();
+			info.genre = tag.genre().unwrap_or_default().to_string();
+			info.year = tag.year();
+			info.track_number = tag.track();
+		}
+		info
+	}
+
+	fn to_library_track(&self) -> LibraryTrack {
+		LibraryTrack {
+			id: self.id,
+			title: self.title.clone(),
+			album: self.album.clone(),
+			artist: self.artist.clone(),
+			track_number: self.track_number,
+			year: self.year,
+		}
+	}
+}
+
+/// Stable track ID: FNV-1a hash of the path relative to the library root, so
+/// it survives restarts, rescans and moving the library as a whole
+pub fn track_id(root: &Path, path: &Path) -> u64 {
+	let relative = path.strip_prefix(root).unwrap_or(path);
+	let mut hash: u64 = 0xcbf29ce484222325;
+	for (i, component) in relative.components().enumerate() {
+		if i != 0 {
+			hash ^= b'/' as u64;
+			hash = hash.wrapping_mul(0x100000001b3);
+		}
+		for byte in component.as_os_str().to_string_lossy().bytes() {
+			hash ^= byte as u64;
+			hash = hash.wrapping_mul(0x100000001b3);
+		}
+	}
+	hash
+}
+
+pub struct Library {
+	pub tracks: Vec<TrackInfo>,
+}
+
+impl Library {
+	pub fn scan(root: &Path, tracklist: &[PathBuf]) -> Self {
+		Self {
+			tracks: tracklist.iter().map(|x| TrackInfo::read(root, x)).collect(),
+		}
+	}
+
+	pub fn get(&self, id: u64) -> Option<&TrackInfo> {
+		self.tracks.iter().find(|x| x.id == id)
+	}
+
+	/// Tracks of the album in order
+	pub fn album(&self, artist: &str, album: &str) -> Vec<&TrackInfo> {
+		let mut tracks: Vec<&TrackInfo> =
+			self.tracks.iter().filter(|x| x.artist == artist && x.album == album).collect();
+		tracks
+			.sort_by(|a, b| a.track_number.cmp(&b.track_number).then_with(|| a.path.cmp(&b.path)));
+		tracks
+	}
+
+	pub fn artist(&self, artist: &str) -> Vec<&TrackInfo> {
+		self.tracks.iter().filter(|x| x.artist == artist).collect()
+	}
+
+	pub fn browse(&self, browse: &Browse, page: Page) -> LibraryResponce {
+		let items: Vec<LibraryItem> = match browse {
+			Browse::Artists => self
+				.tracks
+				.iter()
+				.map(|x| x.artist.as_str())
+				.collect::<BTreeSet<&str>>()
+				.into_iter()
+				.map(|x| LibraryItem::Artist(x.to_string()))
+				.collect(),
+			Browse::Albums(artist) => self
+				.tracks
+				.iter()
+				.filter(|x| &x.artist == artist)
+				.map(|x| x.album.as_str())
+				.collect::<BTreeSet<&str>>()
+				.into_iter()
+				.map(|x| LibraryItem::Album(x.to_string()))
+				.collect(),
+			Browse::Tracks(artist, album) => self
+				.album(artist, album)
+				.into_iter()
+				.map(|x| LibraryItem::Track(x.to_library_track()))
+				.collect(),
+		};
+		paginate(items, page)
+	}
+
+	/// Every whitespace-separated term must be found in title, artist or album
+	pub fn search(&self, query: &str, page: Page) -> LibraryResponce {
+		let terms: Vec<String> = query.split_whitespace().map(|x| x.to_lowercase()).collect();
+		let items = self
+			.tracks
+			.iter()
+			.filter(|track| {
+				let haystack =
+					format!("{}\n{}\n{}", track.title, track.artist, track.album).to_lowercase();
+				terms.iter().all(|term| haystack.contains(term))
+			})
+			.map(|x| LibraryItem::Track(x.to_library_track()))
+			.collect();
+		paginate(items, page)
+	}
+}
+
+fn paginate(items: Vec<LibraryItem>, page: Page) -> LibraryResponce {
+	LibraryResponce {
+		total: items.len() as u64,
+		items: items
+			.into_iter()
+			.skip(page.offset as usize)
+			.take(page.limit.min(MAX_PAGE) as usize)
+			.collect(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn track(path: &str, artist: &str, album: &str, title: &str, number: Option<u32>) -> TrackInfo {
+		TrackInfo {
+			id: track_id(Path::new("/"), Path::new(path)),
+			path: path.into(),
+			title: title.to_string(),
+			artist: artist.to_string(),
+			album: album.to_string(),
+			track_number: number,
+			..Default::default()
+		}
+	}
+
+	fn library() -> Library {
+		Library {
+			tracks: vec![
+				track("/b/2.flac", "Beta", "Second", "So What", Some(2)),
+				track("/a/1.flac", "Alpha", "First", "Intro", None),
+				track("/b/1.flac", "Beta", "Second", "Blue in Green", Some(1)),
+				track("/b/0.flac", "Beta", "First", "Freddie", Some(1)),
+				track("/b/3.flac", "Beta", "Second", "All Blues", None),
+			],
+		}
+	}
+
+	fn page(offset: u32, limit: u32) -> Page {
+		Page {
+			offset,
+			limit,
+		}
+	}
+
+	fn titles(responce: &LibraryResponce) -> Vec<&str> {
+		responce
+			.items
+			.iter()
+			.map(|x| match x {
+				LibraryItem::Track(track) => track.title.as_str(),
+				_ => panic!("not a track: {:?}", x),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn stable_ids() {
+		let id = track_id(Path::new("/music"), Path::new("/music/artist/album/01.flac"));
+		// FNV-1a of "artist/album/01.flac", must never change
+		assert_eq!(id, 0x4e4bc116fd8474ec);
+		assert_eq!(id, track_id(Path::new("/music"), Path::new("/music/artist/album/01.flac")));
+		// Moving the library as a whole
+		assert_eq!(
+			id,
+			track_id(Path::new("/mnt/music/"), Path::new("/mnt/music/artist/album/01.flac"))
+		);
+		// Different files
+		let root = Path::new("/music");
+		let ids: BTreeSet<u64> = [
+			"/music/artist/album/02.flac",
+			"/music/artist/album01.flac",
+			"/music/other/album/01.flac",
+			"/music/01.flac",
+		]
+		.iter()
+		.map(|x| track_id(root, Path::new(x)))
+		.collect();
+		assert_eq!(ids.len(), 4);
+		assert!(!ids.contains(&id));
+		// Rescans
+		let tracklist: Vec<PathBuf> =
+			ids.iter().map(|x| PathBuf::from(format!("/music/{}", x))).collect();
+		let scanned = |library: Library| library.tracks.iter().map(|x| x.id).collect::<Vec<u64>>();
+		assert_eq!(
+			scanned(Library::scan(root, &tracklist)),
+			scanned(Library::scan(root, &tracklist))
+		);
+	}
+
+	#[test]
+	fn browse_sorted() {
+		let library = library();
+		assert_eq!(
+			library.browse(&Browse::Artists, page(0, 10)).items,
+			vec![LibraryItem::Artist("Alpha".into()), LibraryItem::Artist("Beta".into())]
+		);
+		assert_eq!(
+			library.browse(&Browse::Albums("Beta".into()), page(0, 10)).items,
+			vec![LibraryItem::Album("First".into()), LibraryItem::Album("Second".into())]
+		);
+		// By track number, then by path
+		let tracks = library.browse(&Browse::Tracks("Beta".into(), "Second".into()), page(0, 10));
+		assert_eq!(titles(&tracks), ["All Blues", "Blue in Green", "So What"]);
+		assert!(library.browse(&Browse::Albums("Gamma".into()), page(0, 10)).items.is_empty());
+	}
+
+	#[test]
+	fn pagination() {
+		let library = library();
+		let tracks = Browse::Tracks("Beta".into(), "Second".into());
+		let responce = library.browse(&tracks, page(1, 1));
+		assert_eq!(responce.total, 3);
+		assert_eq!(titles(&responce), ["Blue in Green"]);
+		// Past the end
+		let responce = library.browse(&tracks, page(3, 10));
+		assert_eq!(responce.total, 3);
+		assert!(responce.items.is_empty());
+		let responce = library.browse(&tracks, page(u32::MAX, u32::MAX));
+		assert!(responce.items.is_empty());
+		// Only the total
+		let responce = library.browse(&tracks, page(0, 0));
+		assert_eq!(responce.total, 3);
+		assert!(responce.items.is_empty());
+	}
+
+	#[test]
+	fn page_is_capped() {
+		let library = Library {
+			tracks: (0..MAX_PAGE + 10)
+				.map(|x| track(&format!("/{}.flac", x), "A", "B", "C", None))
+				.collect(),
+		};
+		let responce = library.browse(&Browse::Tracks("A".into(), "B".into()), page(0, u32::MAX));
+		assert_eq!(responce.total, (MAX_PAGE + 10) as u64);
+		assert_eq!(responce.items.len(), MAX_PAGE as usize);
+	}
+
+	#[test]
+	fn search() {
+		let library = library();
+		// Case-insensitive, every term in any field
+		assert_eq!(titles(&library.search("BLUE", page(0, 10))), ["Blue in Green", "All Blues"]);
+		assert_eq!(titles(&library.search("beta first", page(0, 10))), ["Freddie"]);
+		assert_eq!(titles(&library.search("  intro  ", page(0, 10))), ["Intro"]);
+		assert!(library.search("blue intro", page(0, 10)).items.is_empty());
+		// Nothing to look for matches everything
+		assert_eq!(library.search("", page(0, 10)).total, 5);
+	}
+
+	#[test]
+	fn album_and_artist() {
+		let library = library();
+		let album: Vec<&str> =
+			library.album("Beta", "Second").iter().map(|x| x.title.as_str()).collect();
+		assert_eq!(album, ["All Blues", "Blue in Green", "So What"]);
+		assert_eq!(library.artist("Beta").len(), 4);
+		assert!(library.artist("beta").is_empty());
+		let id = library.tracks[1].id;
+		assert_eq!(library.get(id).unwrap().title, "Intro");
+		assert!(library.get(id ^ 1).is_none());
+	}
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use clap::Parser;
+use once_cell::sync::Lazy;
+
+use crate::Args;
+
+static LIMITS: Lazy<Limits> = Lazy::new(|| {
+	let args = Args::parse();
+	Limits::new(args.max_listeners, args.max_listeners_per_ip)
+});
+
+/// `--max-listeners` and `--max-listeners-per-ip` with connections counted
+/// against them
+pub struct Limits {
+	max_listeners: Option<usize>,
+	max_per_ip: Option<usize>,
+	/// Connections by client address, `None` for clients without one (Unix
+	/// sockets)
+	listeners: Mutex<HashMap<Option<IpAddr>, usize>>,
+}
+
+impl Limits {
+	pub fn new(max_listeners: Option<usize>, max_per_ip: Option<usize>) -> Self {
+		Self {
+			max_listeners,
+			max_per_ip,
+			listeners: Default::default(),
+		}
+	}
+
+	/// None if any limit is reached
+	pub fn acquire(&'static self, ip: Option<IpAddr>) -> Option<Slot> {
+		let mut listeners = self.listeners.lock().unwrap();
+		let total: usize = listeners.values().sum();
+		let same_ip = listeners.get(&ip).copied().unwrap_or_default();
+		if self.max_listeners.is_some_and(|x| total >= x)
+			|| (ip.is_some() && self.max_per_ip.is_some_and(|x| same_ip >= x))
+		{
+			return None;
+		}
+		*listeners.entry(ip).or_default() += 1;
+		Some(Slot {
+			ip,
+			limits: self,
+		})
+	}
+}
+
+/// Counts a connection against the limits while alive
+pub struct Slot {
+	ip: Option<IpAddr>,
+	limits: &'static Limits,
+}
+
+impl Slot {
+	/// Slot from the command line limits, None if any is reached
+	pub fn acquire(ip: Option<IpAddr>) -> Option<Self> {
+		LIMITS.acquire(ip)
+	}
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		let mut listeners = self.limits.listeners.lock().unwrap();
+		if let Some(count) = listeners.get_mut(&self.ip) {
+			*count -= 1;
+			if *count == 0 {
+				listeners.remove(&self.ip);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn limits(max_listeners: Option<usize>, max_per_ip: Option<usize>) -> &'static Limits {
+		Box::leak(Box::new(Limits::new(max_listeners, max_per_ip)))
+	}
+
+	#[test]
+	fn per_ip() {
+		let limits = limits(None, Some(2));
+		let (a, b) = (Some("10.0.0.1".parse().unwrap()), Some("10.0.0.2".parse().unwrap()));
+		let first = limits.acquire(a).unwrap();
+		let _second = limits.acquire(a).unwrap();
+		assert!(limits.acquire(a).is_none());
+		let _other = limits.acquire(b).unwrap();
+		// Unix socket clients have no address to limit
+		let _unix: Vec<Slot> = (0..5).map(|_| limits.acquire(None).unwrap()).collect();
+		drop(first);
+		assert!(limits.acquire(a).is_some());
+	}
+
+	#[test]
+	fn global() {
+		let limits = limits(Some(3), None);
+		let a = Some("10.0.0.1".parse().unwrap());
+		let slots: Vec<Slot> = (0..3).map(|_| limits.acquire(a).unwrap()).collect();
+		assert!(limits.acquire(a).is_none());
+		assert!(limits.acquire(Some("10.0.0.2".parse().unwrap())).is_none());
+		assert!(limits.acquire(None).is_none());
+		drop(slots);
+		assert!(limits.acquire(None).is_some());
+	}
+
+	#[test]
+	fn released_on_drop() {
+		let limits = limits(Some(2), Some(1));
+		let a = Some("::1".parse().unwrap());
+		let slot = limits.acquire(a).unwrap();
+		assert_eq!(limits.listeners.lock().unwrap().get(&a), Some(&1));
+		drop(slot);
+		// Addresses gone for good don't stay in the map
+		assert!(limits.listeners.lock().unwrap().is_empty());
+		let _slots = (limits.acquire(a).unwrap(), limits.acquire(None).unwrap());
+		assert_eq!(limits.listeners.lock().unwrap().values().sum::<usize>(), 2);
+	}
+}
@@ -0,0 +1,299 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use tracing::error;
+
+use crate::transport::Transport;
+
+/// Addresses with this prefix are Unix socket paths
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Clients have this long for every read and write until the stream starts
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Failed accept (e.g. out of file descriptors) is logged and retried a bit
+/// later
+pub async fn accept_failed(e: std::io::Error) {
+	error!("Failed to accept connection: {}", e);
+	tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+/// Listener for lonelyradio protocol connections
+pub enum Listener {
+	Tcp(TcpListener),
+	#[cfg(unix)]
+	Unix(UnixListener),
+}
+
+impl Listener {
+	/// Binds "address:port" or "unix:/path". Stale Unix socket is removed. IPv6
+	/// addresses don't accept IPv4 clients, so "0.0.0.0" and "[::]" could be
+	/// bound together on every platform
+	pub async fn bind(address: &str) -> Self {
+		if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+			#[cfg(unix)]
+			{
+				use std::os::unix::fs::FileTypeExt;
+				if std::fs::metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+					let _ = std::fs::remove_file(path);
+				}
+				return Self::Unix(
+					UnixListener::bind(path)
+						.unwrap_or_else(|e| panic!("failed to bind {:?}: {}", path, e)),
+				);
+			}
+			#[cfg(not(unix))]
+			panic!("Unix sockets are not supported on this platform: {:?}", path)
+		}
+		let resolved = tokio::net::lookup_host(address)
+			.await
+			.ok()
+			.and_then(|mut x| x.next())
+			.unwrap_or_else(|| panic!("failed to resolve {:?}", address));
+		Self::Tcp(
+			bind_tcp(resolved).unwrap_or_else(|e| panic!("failed to bind {:?}: {}", address, e)),
+		)
+	}
+
+	/// Sockets passed by systemd socket activation, if any
+	#[cfg(unix)]
+	pub fn inherited() -> Vec<Self> {
+		use std::os::fd::{FromRawFd, IntoRawFd};
+		let fds = passed(
+			std::env::var("LISTEN_PID").ok().as_deref(),
+			std::env::var("LISTEN_FDS").ok().as_deref(),
+			std::process::id(),
+		);
+		// Not for child processes
+		for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+			std::env::remove_var(name);
+		}
+		fds.map(|fd| {
+			// Descriptors are ours from now on. Only Internet sockets have an
+			// address std understands
+			let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+			listener.set_nonblocking(true).unwrap();
+			if listener.local_addr().is_ok() {
+				return Self::Tcp(TcpListener::from_std(listener).unwrap());
+			}
+			let listener =
+				unsafe { std::os::unix::net::UnixListener::from_raw_fd(listener.into_raw_fd()) };
+			Self::Unix(UnixListener::from_std(listener).unwrap())
+		})
+		.collect()
+	}
+
+	#[cfg(not(unix))]
+	pub fn inherited() -> Vec<Self> {
+		vec![]
+	}
+
+	/// Accepted connection in blocking mode
+	pub async fn accept(&self) -> std::io::Result<Socket> {
+		match self {
+			Self::Tcp(listener) => {
+				let s = listener.accept().await?.0.into_std()?;
+				s.set_nonblocking(false)?;
+				Ok(Socket::Tcp(s))
+			}
+			#[cfg(unix)]
+			Self::Unix(listener) => {
+				let s = listener.accept().await?.0.into_std()?;
+				s.set_nonblocking(false)?;
+				Ok(Socket::Unix(s, None))
+			}
+		}
+	}
+}
+
+/// Descriptors passed in LISTEN_FDS, if LISTEN_PID is the process `ours`
+#[cfg(unix)]
+fn passed(pid: Option<&str>, count: Option<&str>, ours: u32) -> std::ops::Range<i32> {
+	// First passed descriptor, see sd_listen_fds(3)
+	const LISTEN_FDS_START: i32 = 3;
+	let pid = pid.and_then(|x| x.parse::<u32>().ok());
+	let count = count.and_then(|x| x.parse::<i32>().ok());
+	match (pid, count) {
+		(Some(pid), Some(count)) if pid == ours && count > 0 => {
+			LISTEN_FDS_START..LISTEN_FDS_START + count
+		}
+		_ => 0..0,
+	}
+}
+
+fn bind_tcp(address: SocketAddr) -> std::io::Result<TcpListener> {
+	let socket = socket2::Socket::new(
+		socket2::Domain::for_address(address),
+		socket2::Type::STREAM,
+		Some(socket2::Protocol::TCP),
+	)?;
+	if address.is_ipv6() {
+		socket.set_only_v6(true)?;
+	}
+	// Same as std does, so restarts don't wait for TIME_WAIT
+	#[cfg(unix)]
+	socket.set_reuse_address(true)?;
+	socket.set_nonblocking(true)?;
+	socket.bind(&address.into())?;
+	socket.listen(1024)?;
+	TcpListener::from_std(socket.into())
+}
+
+/// Connection accepted by a `Listener`
+pub enum Socket {
+	Tcp(TcpStream),
+	/// UnixStream::peek is unstable, so the byte read by `readable()` is kept
+	/// here
+	#[cfg(unix)]
+	Unix(UnixStream, Option<u8>),
+}
+
+impl Socket {
+	pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+		match self {
+			Self::Tcp(s) => s.set_nonblocking(nonblocking),
+			#[cfg(unix)]
+			Self::Unix(s, _) => s.set_nonblocking(nonblocking),
+		}
+	}
+}
+
+impl Read for Socket {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self {
+			Self::Tcp(s) => s.read(buf),
+			#[cfg(unix)]
+			Self::Unix(s, peeked) => match (peeked.take(), buf.first_mut()) {
+				(Some(byte), Some(first)) => {
+					*first = byte;
+					Ok(1)
+				}
+				(byte, _) => {
+					*peeked = byte;
+					s.read(buf)
+				}
+			},
+		}
+	}
+}
+
+impl Write for Socket {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			Self::Tcp(s) => s.write(buf),
+			#[cfg(unix)]
+			Self::Unix(s, _) => s.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			Self::Tcp(s) => s.flush(),
+			#[cfg(unix)]
+			Self::Unix(s, _) => s.flush(),
+		}
+	}
+}
+
+impl Transport for Socket {
+	fn peer(&self) -> Option<std::net::IpAddr> {
+		match self {
+			Self::Tcp(s) => s.peer(),
+			#[cfg(unix)]
+			Self::Unix(..) => None,
+		}
+	}
+
+	fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		match self {
+			Self::Tcp(s) => s.set_timeout(timeout),
+			#[cfg(unix)]
+			Self::Unix(s, _) => {
+				s.set_read_timeout(timeout)?;
+				s.set_write_timeout(timeout)
+			}
+		}
+	}
+
+	fn readable(&mut self) -> bool {
+		match self {
+			Self::Tcp(s) => s.readable(),
+			#[cfg(unix)]
+			Self::Unix(s, peeked) => {
+				if peeked.is_some() {
+					return true;
+				}
+				if s.set_nonblocking(true).is_err() {
+					return true;
+				}
+				let mut byte = [0u8];
+				let read = s.read(&mut byte);
+				if s.set_nonblocking(false).is_err() {
+					return true;
+				}
+				match read {
+					Ok(1) => {
+						*peeked = Some(byte[0]);
+						true
+					}
+					Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => false,
+					_ => true,
+				}
+			}
+		}
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn systemd_variables() {
+		assert_eq!(passed(Some("42"), Some("2"), 42), 3..5);
+		assert_eq!(passed(Some("42"), Some("1"), 42), 3..4);
+		// Meant for another process, e.g. our parent
+		assert_eq!(passed(Some("41"), Some("2"), 42), 0..0);
+		assert_eq!(passed(None, Some("2"), 42), 0..0);
+		assert_eq!(passed(Some("42"), None, 42), 0..0);
+		assert_eq!(passed(Some("42"), Some("0"), 42), 0..0);
+		assert_eq!(passed(Some("42"), Some("-1"), 42), 0..0);
+		assert_eq!(passed(Some("x"), Some("2"), 42), 0..0);
+	}
+
+	#[tokio::test]
+	async fn unix_socket() {
+		let path = std::env::temp_dir().join(format!("lonelyradio-{}.sock", std::process::id()));
+		let address = format!("{}{}", UNIX_PREFIX, path.display());
+		drop(Listener::bind(&address).await);
+		// Stale socket from the previous run is replaced
+		let listener = Listener::bind(&address).await;
+		let mut client = UnixStream::connect(&path).unwrap();
+		let mut s = listener.accept().await.unwrap();
+		assert!(s.peer().is_none());
+		assert!(!s.readable());
+
+		client.write_all(b"lonelyra").unwrap();
+		// Byte read to check is given back
+		while !s.readable() {}
+		let mut hello = [0u8; 8];
+		s.read_exact(&mut hello).unwrap();
+		assert_eq!(&hello, b"lonelyra");
+
+		s.write_all(b"ok").unwrap();
+		let mut reply = [0u8; 2];
+		client.read_exact(&mut reply).unwrap();
+		assert_eq!(&reply, b"ok");
+		drop(client);
+		assert!(s.readable());
+		assert_eq!(s.read(&mut reply).unwrap(), 0);
+		std::fs::remove_file(path).unwrap();
+	}
+}
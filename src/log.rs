@@ -0,0 +1,167 @@
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use tracing::{info, info_span, Span, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+	Text,
+	/// One JSON object per line
+	Json,
+}
+
+/// Logs to stdout or appends to `file`
+pub fn init(format: LogFormat, level: LevelFilter, file: Option<&Path>) {
+	let writer = match file {
+		None => BoxMakeWriter::new(std::io::stdout),
+		Some(path) => BoxMakeWriter::new(Mutex::new(
+			OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.unwrap_or_else(|e| panic!("failed to open log file {:?}: {}", path, e)),
+		)),
+	};
+	subscriber(format, level, writer, file.is_none() && std::io::stdout().is_terminal()).init()
+}
+
+fn subscriber(
+	format: LogFormat,
+	level: LevelFilter,
+	writer: BoxMakeWriter,
+	ansi: bool,
+) -> Box<dyn Subscriber + Send + Sync> {
+	let builder = tracing_subscriber::fmt()
+		.with_max_level(level)
+		.with_ansi(ansi)
+		.with_target(false)
+		.with_writer(writer);
+	match format {
+		LogFormat::Text => Box::new(builder.finish()),
+		LogFormat::Json => Box::new(builder.json().finish()),
+	}
+}
+
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// One client connection. Logs connection, and disconnection with what was
+/// sent to the client
+pub struct Session {
+	span: Span,
+	pub bytes: u64,
+	pub tracks: u64,
+}
+
+impl Session {
+	pub fn new(peer: Option<IpAddr>) -> Self {
+		let span = info_span!(
+			"session",
+			id = SESSIONS.fetch_add(1, Ordering::Relaxed),
+			peer = peer.map(tracing::field::display)
+		);
+		info!(parent: &span, "connected");
+		Self {
+			span,
+			bytes: 0,
+			tracks: 0,
+		}
+	}
+
+	/// Everything about the session is logged in it
+	pub fn span(&self) -> &Span {
+		&self.span
+	}
+}
+
+impl Drop for Session {
+	fn drop(&mut self) {
+		info!(parent: &self.span, bytes = self.bytes, tracks = self.tracks, "disconnected");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+	use std::sync::Arc;
+
+	use tracing::debug;
+
+	use super::*;
+
+	/// Everything logged while running `f`
+	fn capture(format: LogFormat, level: LevelFilter, f: impl FnOnce()) -> String {
+		#[derive(Clone)]
+		struct Buffer(Arc<Mutex<Vec<u8>>>);
+		impl Write for Buffer {
+			fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+				self.0.lock().unwrap().write(buf)
+			}
+
+			fn flush(&mut self) -> std::io::Result<()> {
+				Ok(())
+			}
+		}
+		let buffer = Buffer(Default::default());
+		let writer = {
+			let buffer = buffer.clone();
+			BoxMakeWriter::new(move || buffer.clone())
+		};
+		tracing::subscriber::with_default(subscriber(format, level, writer, false), f);
+		let logged = buffer.0.lock().unwrap().clone();
+		String::from_utf8(logged).unwrap()
+	}
+
+	fn session() {
+		let mut session = Session::new(Some("192.168.1.20".parse().unwrap()));
+		let _entered = session.span().clone().entered();
+		info!(artist = "Miles Davis", "playing");
+		debug!("details");
+		session.bytes = 1024;
+		session.tracks = 2;
+	}
+
+	#[test]
+	fn text() {
+		let logged = capture(LogFormat::Text, LevelFilter::INFO, session);
+		let lines: Vec<&str> = logged.lines().collect();
+		assert_eq!(lines.len(), 3, "{}", logged);
+		assert!(lines.iter().all(|x| x.contains("session{id=") && x.contains("peer=192.168.1.20")));
+		assert!(lines[0].ends_with("connected"));
+		assert!(lines[1].ends_with("playing artist=\"Miles Davis\""));
+		assert!(lines[2].ends_with("disconnected bytes=1024 tracks=2"));
+		assert!(capture(LogFormat::Text, LevelFilter::DEBUG, session).contains("details"));
+	}
+
+	#[test]
+	fn json() {
+		let logged = capture(LogFormat::Json, LevelFilter::INFO, session);
+		let lines: Vec<&str> = logged.lines().collect();
+		assert_eq!(lines.len(), 3, "{}", logged);
+		// One object per line
+		assert!(lines.iter().all(|x| x.starts_with('{') && x.ends_with('}')));
+		assert!(lines.iter().all(|x| x.contains("\"peer\":\"192.168.1.20\"")));
+		assert!(lines[1].contains("\"artist\":\"Miles Davis\""));
+		assert!(lines[2].contains("\"bytes\":1024") && lines[2].contains("\"tracks\":2"));
+	}
+
+	#[test]
+	fn session_ids() {
+		let ids: Vec<String> = (0..2)
+			.map(|_| {
+				let logged =
+					capture(LogFormat::Json, LevelFilter::INFO, || drop(Session::new(None)));
+				let id = logged.split("\"id\":").nth(1).unwrap();
+				id[..id.find([',', '}']).unwrap()].to_string()
+			})
+			.collect();
+		assert_ne!(ids[0], ids[1]);
+	}
+}
@@ -1,47 +1,87 @@
+mod auth;
+mod cidr;
+mod config;
 mod decode;
 mod encode;
+mod icecast;
+mod library;
+mod limits;
+mod listen;
+mod log;
+mod metrics;
+mod notice;
+mod playlist;
+mod query;
+mod shutdown;
+mod source;
+mod status;
+mod tls;
+mod transport;
+mod web;
 
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::io::Read;
+use std::net::IpAddr;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use chrono::Local;
 use clap::Parser;
-use encode::encode;
+use encode::encode_bounded;
 use futures_util::pin_mut;
 use futures_util::StreamExt;
 use image::ImageReader;
 use image::RgbImage;
 use lofty::Accessor;
 use lofty::TaggedFileExt;
+use lonelyradio_types::Cipher;
+use lonelyradio_types::Control;
 use lonelyradio_types::Encoder;
+use lonelyradio_types::Feature;
+use lonelyradio_types::Framing;
+use lonelyradio_types::Notice;
 use lonelyradio_types::Request;
+use lonelyradio_types::RequestError;
+use lonelyradio_types::RequestKind;
 use lonelyradio_types::RequestResult;
 use lonelyradio_types::ServerCapabilities;
 use lonelyradio_types::Settings;
-use lonelyradio_types::{FragmentMetadata, PlayMessage, TrackMetadata};
+use lonelyradio_types::{
+	write_frame, FragmentMetadata, PlayMessage, TrackMetadata, PROTOCOL_VERSION, SEAL_OVERHEAD,
+};
 use rand::prelude::*;
 use std::io::Write;
 use tokio::net::TcpListener;
+use tokio::sync::broadcast;
 use tokio_stream::Stream;
-use url::Url;
+use tracing::{info, warn, Instrument, Span};
+use tracing_subscriber::filter::LevelFilter;
 use walkdir::DirEntry;
-use xspf::Playlist;
 
+use crate::auth::Access;
+use crate::config::{network_allows, station_key, CONFIG, KEYS, STATION};
 use crate::decode::decode_file_stream;
 use crate::decode::get_meta;
+use crate::library::Library;
+use crate::limits::Slot;
+use crate::listen::Listener;
+use crate::log::Session;
+use crate::notice::{announce, read_announcements, NOTICES};
+use crate::playlist::{get_dir_playlists, get_playlists, get_smart_playlists};
+use crate::source::{Catalog, TrackSource};
+use crate::transport::Transport;
 
 #[derive(Parser, Clone)]
 struct Args {
 	/// Directory with audio files
 	dir: PathBuf,
 
-	/// Address:port to bind
+	/// Address:port or unix:/path to bind, may be repeated. Ignored with
+	/// systemd socket activation
 	#[arg(short, default_value = "0.0.0.0:5894")]
-	address: String,
+	address: Vec<String>,
 
 	/// Resample all tracks, which samplerate exceeds N
 	#[arg(short, long, default_value = "96000")]
@@ -55,8 +95,74 @@ struct Args {
 	#[arg(long, default_value = "96000")]
 	artwork: i32,
 
+	/// Directory with playlists (XSPF, M3U, M3U8, PLS)
 	#[arg(long)]
 	playlist_dir: Option<PathBuf>,
+
+	/// Replace path prefix in playlist entries (FROM=TO, may be repeated)
+	#[arg(long, value_parser = playlist::parse_remap)]
+	remap: Vec<(PathBuf, PathBuf)>,
+
+	/// Expose every library subdirectory up to depth N as a playlist
+	#[arg(long, value_name = "N")]
+	dir_playlists: Option<usize>,
+
+	/// Server configuration file (TOML)
+	#[arg(long)]
+	config: Option<PathBuf>,
+
+	/// Certificate chain (PEM) to accept TLS connections only
+	#[arg(long, requires = "tls_key")]
+	tls_cert: Option<PathBuf>,
+
+	/// Private key (PEM) of the certificate
+	#[arg(long, requires = "tls_cert")]
+	tls_key: Option<PathBuf>,
+
+	/// Address:port to accept WebSocket connections and serve the web player
+	#[arg(long)]
+	ws_address: Option<String>,
+
+	/// Address:port to serve stations as Icecast-compatible Ogg Vorbis streams
+	#[arg(long)]
+	http_address: Option<String>,
+
+	/// Maximum number of listeners
+	#[arg(long, value_name = "N")]
+	max_listeners: Option<usize>,
+
+	/// Maximum number of listeners from one IP address
+	#[arg(long, value_name = "N")]
+	max_listeners_per_ip: Option<usize>,
+
+	/// Bandwidth ceiling for every listener in kbit/s
+	#[arg(long, value_name = "KBITS")]
+	max_bandwidth: Option<u32>,
+
+	/// Address:port to serve Prometheus metrics at /metrics
+	#[arg(long)]
+	metrics_address: Option<String>,
+
+	/// Log format
+	#[arg(long, value_enum, default_value = "text")]
+	log_format: log::LogFormat,
+
+	/// Log level (off, error, warn, info, debug or trace)
+	#[arg(long, default_value = "info")]
+	log_level: LevelFilter,
+
+	/// Append log to the file instead of printing it
+	#[arg(long)]
+	log_file: Option<PathBuf>,
+
+	/// Seconds to let listeners finish current tracks on SIGTERM or SIGINT
+	#[arg(long, default_value = "0")]
+	shutdown_deadline: u64,
+
+	/// Seconds after which listeners should reconnect on shutdown, if the
+	/// server will be back (e.g. on restart)
+	#[arg(long)]
+	reconnect_after: Option<u32>,
 }
 
 const SUPPORTED_ENCODERS: &[Encoder] = &[
@@ -72,21 +178,76 @@ const SUPPORTED_ENCODERS: &[Encoder] = &[
 	Encoder::Sea,
 ];
 
+/// Encoders fitting into --max-bandwidth
+fn encoders() -> Vec<Encoder> {
+	let max = Args::parse().max_bandwidth;
+	SUPPORTED_ENCODERS
+		.iter()
+		.copied()
+		.filter(|x| match (max, min_bitrate(*x)) {
+			(Some(max), Some(min)) => min <= max,
+			_ => true,
+		})
+		.collect()
+}
+
+/// Bitrate of CD audio in kbit/s, if it doesn't depend on the audio itself
+fn min_bitrate(encoder: Encoder) -> Option<u32> {
+	match encoder {
+		Encoder::Pcm16 => Some(1411),
+		Encoder::PcmFloat => Some(2822),
+		_ => None,
+	}
+}
+
+/// Time to deliver the shutdown notice after the deadline
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Control messages aren't framed, so they are read with their own limits
+const MAX_CONTROL: u64 = 4096;
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Client after the handshake
+struct Client {
+	/// Negotiated protocol version
+	version: u16,
+	access: Access,
+	/// Station being played ("" for the whole library)
+	station: String,
+}
+
+/// Why streaming of a track has ended
+enum Outcome {
+	Finished,
+	Disconnected,
+	/// Control message and number of samples sent before it
+	Control(Control, usize),
+}
+
 async fn stream_track(
 	samples_stream: impl Stream<Item = Vec<f32>>,
 	md: TrackMetadata,
-	mut s: impl Write,
-) -> bool {
+	s: &mut impl Transport,
+	mut skip: usize,
+	output: &mut Output,
+	sequence: &mut u64,
+	notices: &mut Option<broadcast::Receiver<Notice>>,
+) -> Outcome {
 	pin_mut!(samples_stream);
 
-	let _md = md.clone();
-
-	if s.write_all(rmp_serde::encode::to_vec_named(&PlayMessage::T(_md)).unwrap().as_slice())
-		.is_err()
-	{
-		return true;
+	let mut _md = rmp_serde::encode::to_vec_named(&PlayMessage::T(md.clone())).unwrap();
+	if output.max_message().is_some_and(|max| _md.len() > max) {
+		_md = rmp_serde::encode::to_vec_named(&PlayMessage::T(TrackMetadata {
+			cover: None,
+			..md.clone()
+		}))
+		.unwrap();
+	}
+	if !output.send(s, &_md) {
+		return Outcome::Disconnected;
 	};
 
+	let mut sent = skip;
 	// Why chunks?
 	// Different codecs have different quality on different audio lenghts
 	while let Some(mut _samples) = samples_stream
@@ -105,6 +266,21 @@ async fn stream_track(
 	{
 		let mut _samples = _samples.concat();
 
+		// Resuming the track after encoder change
+		if skip > 0 {
+			let n = skip.min(_samples.len());
+			_samples.drain(..n);
+			skip -= n;
+			if _samples.is_empty() {
+				continue;
+			}
+		}
+		let mut offset = sent;
+		sent += _samples.len();
+		let duration = Duration::from_secs_f64(
+			_samples.len() as f64 / md.channels as f64 / md.sample_rate as f64,
+		);
+
 		match md.encoder {
 			Encoder::Pcm16
 			| Encoder::PcmFloat
@@ -112,58 +288,233 @@ async fn stream_track(
 			| Encoder::Alac
 			| Encoder::Vorbis
 			| Encoder::Sea => {
-				let (encoded, magic_cookie) =
-					encode(md.encoder, _samples, md.sample_rate, md.channels).unwrap();
-				let _md = PlayMessage::F(FragmentMetadata {
-					length: encoded.as_slice().len() as u64,
-					magic_cookie,
-				});
-				if s.write_all(rmp_serde::to_vec_named(&_md).unwrap().as_slice()).is_err() {
-					return true;
-				}
-				if s.write_all(encoded.as_slice()).is_err() {
-					return true;
+				let start = Instant::now();
+				let fragments = encode_bounded(
+					md.encoder,
+					_samples,
+					md.sample_rate,
+					md.channels,
+					output.max_fragment(),
+				)
+				.unwrap();
+				metrics::encoded(md.encoder, start.elapsed(), fragments.len());
+				for ((encoded, magic_cookie), length) in fragments {
+					let _md = PlayMessage::F(FragmentMetadata {
+						length: encoded.as_slice().len() as u64,
+						magic_cookie,
+						offset: Some((offset / md.channels as usize) as u64),
+						sequence: Some(*sequence),
+					});
+					offset += length;
+					*sequence += 1;
+					if !output.send(s, rmp_serde::to_vec_named(&_md).unwrap().as_slice()) {
+						return Outcome::Disconnected;
+					}
+					if !output.send(s, encoded.as_slice()) {
+						return Outcome::Disconnected;
+					}
 				}
 			}
 			Encoder::Aac | Encoder::Opus | Encoder::WavPack => unimplemented!(),
 		}
+		output.pace(duration).await;
+
+		// Checked before sending notices, so the shutdown one is delivered
+		let expired = shutdown::expired();
+		if let Some(notices) = notices.as_mut() {
+			loop {
+				match notices.try_recv() {
+					Ok(notice) => {
+						if !send_notice(s, output, notice) {
+							return Outcome::Disconnected;
+						}
+					}
+					// Missed some, sending the rest
+					Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+					Err(_) => break,
+				}
+			}
+		}
+
+		if expired {
+			return Outcome::Disconnected;
+		}
+
+		if s.readable() {
+			return match read_control(s) {
+				Some(control) => Outcome::Control(control, sent),
+				None => Outcome::Disconnected,
+			};
+		}
 	}
-	false
+	Outcome::Finished
 }
 
-fn get_playlists(dir: impl AsRef<Path>) -> Option<HashMap<String, Arc<Vec<PathBuf>>>> {
-	let mut map: HashMap<String, Arc<Vec<PathBuf>>> = HashMap::new();
-	for playlist in walkdir::WalkDir::new(dir)
-		.into_iter()
-		.filter_entry(is_not_hidden)
-		.filter_map(|v| v.ok())
-		.map(|x| x.into_path())
-		.filter(|x| x.is_file())
-	{
-		let mut name = playlist.file_name().unwrap().to_str().unwrap().to_string();
-		let parsed = Playlist::read_file(playlist).unwrap();
-		if let Some(ref n) = parsed.title {
-			name = n.clone();
-		}
-		let tracklist = parsed
-			.track_list
-			.iter()
-			.flat_map(|x| x.location.iter().flat_map(|l| Url::parse(l.as_str()).ok()))
-			.filter(|x| x.scheme() == "file")
-			.map(|x| x.to_file_path().unwrap())
-			.filter(|x| track_valid(x))
-			.collect();
-		map.insert(name, Arc::new(tracklist));
+/// None if the client has disconnected or sent something else. A partial
+/// Control message doesn't hold up the stream for longer than CONTROL_TIMEOUT
+fn read_control(s: &mut impl Transport) -> Option<Control> {
+	s.set_timeout(Some(CONTROL_TIMEOUT)).ok()?;
+	let control = rmp_serde::from_read(Read::take(&mut *s, MAX_CONTROL)).ok()?;
+	s.set_timeout(None).ok()?;
+	Some(control)
+}
+
+fn send_notice(s: &mut impl Transport, output: &mut Output, notice: Notice) -> bool {
+	output.send(s, &rmp_serde::to_vec_named(&PlayMessage::N(notice)).unwrap())
+}
+
+/// How PlayMessages and fragments are sent to the client
+struct Output {
+	framing: Option<Framing>,
+	cipher: Option<Cipher>,
+	/// Key of the requested station, only stations with the same key could be
+	/// switched to
+	key: Option<&'static [u8]>,
+	pacing: Option<Pacing>,
+	throttle: Option<Throttle>,
+	/// Counts the connection against listener limits while it lasts
+	_slot: Slot,
+	session: Session,
+}
+
+/// Keeps the client within `--max-bandwidth`
+struct Throttle {
+	pacing: Pacing,
+	/// Bytes per second
+	rate: f64,
+	/// Bytes sent since the last `pace()`
+	sent: usize,
+}
+
+/// Audio sent to a client, which can't apply backpressure
+struct Pacing {
+	lead: Duration,
+	start: Instant,
+	sent: Duration,
+}
+
+impl Pacing {
+	fn new(lead: Duration) -> Self {
+		Self {
+			lead,
+			start: Instant::now(),
+			sent: Duration::ZERO,
+		}
+	}
+
+	/// Waits until the client is at most `lead` ahead of real time
+	async fn pace(&mut self, sent: Duration) {
+		self.sent += sent;
+		let ahead = self.sent.saturating_sub(self.start.elapsed());
+		if ahead > self.lead {
+			tokio::time::sleep(ahead - self.lead).await;
+		}
+	}
+
+	fn reset(&mut self) {
+		self.start = Instant::now();
+		self.sent = Duration::ZERO;
+	}
+}
+
+impl Output {
+	fn new(
+		settings: &Settings,
+		key: Option<&'static [u8]>,
+		lead: Option<Duration>,
+		slot: Slot,
+		session: Session,
+	) -> Self {
+		let framing = match settings.framing {
+			Some(f) => Some(f.effective()),
+			// Encrypted streams are always framed
+			None if key.is_some() => Some(Framing {
+				max_message: u32::MAX,
+				max_fragment: u32::MAX,
+			}),
+			None => None,
+		};
+		Self {
+			framing,
+			cipher: None,
+			key,
+			pacing: lead.map(Pacing::new),
+			// Bursts of a second are fine
+			throttle: Args::parse().max_bandwidth.map(|x| Throttle {
+				pacing: Pacing::new(Duration::from_secs(1)),
+				rate: x as f64 * 1000.0 / 8.0,
+				sent: 0,
+			}),
+			_slot: slot,
+			session,
+		}
+	}
+
+	async fn pace(&mut self, sent: Duration) {
+		if let Some(pacing) = self.pacing.as_mut() {
+			pacing.pace(sent).await;
+		}
+		if let Some(throttle) = self.throttle.as_mut() {
+			let sent = Duration::from_secs_f64(throttle.sent as f64 / throttle.rate);
+			throttle.sent = 0;
+			throttle.pacing.pace(sent).await;
+		}
+	}
+
+	/// Client has dropped buffered audio
+	fn reset_pacing(&mut self) {
+		if let Some(pacing) = self.pacing.as_mut() {
+			pacing.reset();
+		}
+	}
+
+	fn overhead(&self) -> u32 {
+		if self.cipher.is_some() {
+			SEAL_OVERHEAD
+		} else {
+			0
+		}
+	}
+
+	fn max_message(&self) -> Option<usize> {
+		self.framing.map(|f| (f.max_message - self.overhead()) as usize)
+	}
+
+	fn max_fragment(&self) -> Option<usize> {
+		self.framing.map(|f| (f.max_fragment - self.overhead()) as usize)
+	}
+
+	/// Sends serialized PlayMessage or fragment, framed if the client asked for
+	/// it and sealed if the station is encrypted
+	fn send(&mut self, s: &mut impl Transport, data: &[u8]) -> bool {
+		self.session.bytes += data.len() as u64;
+		metrics::bytes_sent(data.len());
+		if let Some(throttle) = self.throttle.as_mut() {
+			throttle.sent += data.len();
+		}
+		match (self.framing, self.cipher.as_mut()) {
+			(Some(_), Some(cipher)) => write_frame(s, &cipher.seal(data)).is_ok(),
+			(Some(_), None) => write_frame(s, data).is_ok(),
+			(None, _) => s.write_all(data).is_ok(),
+		}
 	}
-	Some(map)
 }
 
 #[tokio::main]
 async fn main() {
 	let args = Args::parse();
-	let listener = TcpListener::bind(args.address).await.unwrap();
+	log::init(args.log_format, args.log_level, args.log_file.as_deref());
+	status::init();
+	let mut listeners = Listener::inherited();
+	let inherited = !listeners.is_empty();
+	// Sockets from systemd replace addresses from arguments
+	if !inherited {
+		for address in &args.address {
+			listeners.push(Listener::bind(address).await);
+		}
+	}
 	let tracklist = Arc::new(
-		walkdir::WalkDir::new(args.dir)
+		walkdir::WalkDir::new(&args.dir)
 			.into_iter()
 			.filter_entry(is_not_hidden)
 			.filter_map(|v| v.ok())
@@ -171,105 +522,496 @@ async fn main() {
 			.filter(|x| track_valid(x))
 			.collect::<Vec<PathBuf>>(),
 	);
-	let playlists: Option<HashMap<String, Arc<Vec<PathBuf>>>> = match args.playlist_dir.as_ref() {
-		None => None,
-		Some(dir) => get_playlists(dir),
+	let mut playlists: HashMap<String, Arc<Vec<PathBuf>>> = match args.playlist_dir.as_ref() {
+		None => HashMap::new(),
+		Some(dir) => get_playlists(dir).unwrap_or_default(),
 	};
-	loop {
-		let (socket, _) = listener.accept().await.unwrap();
-		let mut s = socket.into_std().unwrap();
-		s.set_nonblocking(false).unwrap();
+	if let Some(depth) = args.dir_playlists {
+		for (name, tracklist) in get_dir_playlists(&args.dir, &tracklist, depth) {
+			// Playlists from files take precedence
+			playlists.entry(name).or_insert(tracklist);
+		}
+	}
+	let library = Arc::new(Library::scan(&args.dir, &tracklist));
+	info!("Scanned {} tracks", library.tracks.len());
+	playlists.extend(get_smart_playlists(&library.tracks, &CONFIG.smart_playlists));
+	if let Some(station) = STATION.as_ref() {
+		info!("Station: {}", station.name);
+	}
+	if !KEYS.is_empty() {
+		info!("Encrypted stations: {:?}", KEYS.keys().collect::<Vec<_>>());
+	}
+	read_announcements();
+	let catalog = Arc::new(Catalog {
+		tracklist: tracklist.clone(),
+		playlists,
+	});
+	let mut tasks = vec![];
+	if let Some(address) = args.ws_address.as_ref() {
+		let listener = TcpListener::bind(address).await.unwrap();
+		let (catalog, tracklist, library) = (catalog.clone(), tracklist.clone(), library.clone());
+		tasks.push(tokio::spawn(async move {
+			loop {
+				let (socket, address) = match listener.accept().await {
+					Ok(x) => x,
+					Err(e) => {
+						listen::accept_failed(e).await;
+						continue;
+					}
+				};
+				let ip = Some(address.ip().to_canonical());
+				if !accepts(ip) {
+					continue;
+				}
+				let Some(slot) = acquire(ip) else {
+					continue;
+				};
+				let s = socket.into_std().unwrap();
+				s.set_nonblocking(false).unwrap();
+				let (catalog, tracklist, library) =
+					(catalog.clone(), tracklist.clone(), library.clone());
+				tokio::task::spawn_blocking(move || {
+					if let Some(s) = web::accept(s) {
+						handle(s, slot, &catalog, &tracklist, &library)
+					}
+				});
+			}
+		}));
+	}
+	if let Some(address) = args.http_address.as_ref() {
+		if !SUPPORTED_ENCODERS.contains(&Encoder::Vorbis) {
+			panic!("HTTP streams require the vorbis feature")
+		}
+		let listener = TcpListener::bind(address).await.unwrap();
+		let catalog = catalog.clone();
+		tasks.push(tokio::spawn(async move {
+			loop {
+				let (socket, address) = match listener.accept().await {
+					Ok(x) => x,
+					Err(e) => {
+						listen::accept_failed(e).await;
+						continue;
+					}
+				};
+				let ip = Some(address.ip().to_canonical());
+				if !accepts(ip) {
+					continue;
+				}
+				let Some(slot) = acquire(ip) else {
+					continue;
+				};
+				let s = socket.into_std().unwrap();
+				s.set_nonblocking(false).unwrap();
+				let session = Session::new(ip);
+				let catalog = catalog.clone();
+				tokio::task::spawn_blocking(move || icecast::serve(s, slot, catalog, session));
+			}
+		}));
+	}
+	if let Some(address) = args.metrics_address.as_ref() {
+		let listener = TcpListener::bind(address).await.unwrap();
+		tasks.push(tokio::spawn(async move {
+			loop {
+				match listener.accept().await {
+					Ok((socket, _)) => {
+						let s = socket.into_std().unwrap();
+						s.set_nonblocking(false).unwrap();
+						tokio::task::spawn_blocking(|| metrics::serve(s));
+					}
+					Err(e) => listen::accept_failed(e).await,
+				}
+			}
+		}));
+	}
+	let tls = args
+		.tls_cert
+		.as_ref()
+		.zip(args.tls_key.as_ref())
+		.map(|(cert, key)| tls::server_config(cert, key));
+	for listener in listeners {
+		let (catalog, tracklist, library, tls) =
+			(catalog.clone(), tracklist.clone(), library.clone(), tls.clone());
+		tasks.push(tokio::spawn(async move {
+			loop {
+				let s = match listener.accept().await {
+					Ok(s) => s,
+					Err(e) => {
+						listen::accept_failed(e).await;
+						continue;
+					}
+				};
+				if !accepts(s.peer()) {
+					continue;
+				}
+				let Some(slot) = acquire(s.peer()) else {
+					continue;
+				};
+				let (catalog, tracklist, library, tls) =
+					(catalog.clone(), tracklist.clone(), library.clone(), tls.clone());
+				// Slow clients shouldn't hold up the listener
+				tokio::task::spawn_blocking(move || {
+					if s.set_timeout(Some(listen::HANDSHAKE_TIMEOUT)).is_err() {
+						return;
+					}
+					match tls {
+						None => handle(s, slot, &catalog, &tracklist, &library),
+						Some(config) => {
+							if let Some(s) = tls::accept(&config, s) {
+								handle(s, slot, &catalog, &tracklist, &library)
+							}
+						}
+					}
+				});
+			}
+		}));
+	}
 
-		let mut hello = [0u8; 8];
-		if s.read_exact(&mut hello).is_err() {
-			continue;
+	shutdown::signal().await;
+	// Stop accepting
+	tasks.iter().for_each(|x| x.abort());
+	announce(Notice::Shutdown {
+		reconnect: args.reconnect_after,
+	});
+	let deadline = Duration::from_secs(args.shutdown_deadline);
+	shutdown::begin(deadline);
+	tokio::select! {
+		// Streams stuck on writing to slow clients aren't waited for
+		_ = tokio::time::timeout(deadline + SHUTDOWN_GRACE, shutdown::drained()) => {}
+		// Second signal cuts everything off
+		_ = shutdown::signal() => {}
+	}
+	if !inherited {
+		for path in args.address.iter().filter_map(|x| x.strip_prefix(listen::UNIX_PREFIX)) {
+			let _ = std::fs::remove_file(path);
 		}
+	}
+	info!("Shut down");
+	let _ = std::io::stdout().flush();
+	std::process::exit(0);
+}
 
-		if &hello != lonelyradio_types::HELLO_MAGIC {
-			continue;
+fn handle(
+	mut s: impl Transport,
+	slot: Slot,
+	catalog: &Arc<Catalog>,
+	tracklist: &Arc<Vec<PathBuf>>,
+	library: &Library,
+) {
+	let session = Session::new(s.peer());
+	let _entered = session.span().clone().entered();
+	let version = match hello(&mut s) {
+		Ok(Some(version)) => version,
+		Ok(None) => {
+			metrics::rejected("hello");
+			return;
 		}
+		Err(_) => return,
+	};
 
-		if s.write_all(
-			&rmp_serde::to_vec_named(&ServerCapabilities {
-				encoders: SUPPORTED_ENCODERS.to_vec(),
-			})
-			.unwrap(),
-		)
+	let mut client = Client {
+		version,
+		access: Access::new(s.peer()),
+		station: String::new(),
+	};
+	if s.write_all(&rmp_serde::to_vec_named(&capabilities(version, &client.access)).unwrap())
 		.is_err()
-		{
-			continue;
-		};
-		s.flush().unwrap();
+	{
+		return;
+	};
+	if s.flush().is_err() {
+		return;
+	}
 
-		let request: Request = match rmp_serde::from_read(&s) {
+	let mut request: Request = match rmp_serde::from_read(&mut s) {
+		Ok(r) => r,
+		Err(e) => {
+			warn!("Invalid request: {}", e);
+			return;
+		}
+	};
+
+	if let Request::Authenticate(proof) = request {
+		let authenticated = client.access.authenticate(proof);
+		info!(authenticated, "authentication");
+		if !authenticated {
+			metrics::rejected("unauthorized");
+			send_error(s, RequestError::Unauthorized);
+			return;
+		}
+		if s.write_all(&rmp_serde::to_vec_named(&RequestResult::Ok).unwrap()).is_err() {
+			return;
+		}
+		request = match rmp_serde::from_read(&mut s) {
 			Ok(r) => r,
-			Err(_) => {
-				continue;
+			Err(e) => {
+				warn!("Invalid request: {}", e);
+				return;
 			}
 		};
+	}
+	info!(?request, "request");
+	// Everything else gives access to the whole library
+	let station = match &request {
+		Request::PlayPlaylist(playlist, _) => playlist.as_str(),
+		_ => "",
+	};
+	let key = station_key(station);
+	let allowed =
+		matches!(request, Request::ListPlaylist | Request::Authenticate(_) | Request::Status)
+			|| client.access.allows(station);
+	if !allowed {
+		warn!(station, "rejected");
+		metrics::rejected("unauthorized");
+		send_error(s, RequestError::Unauthorized);
+		return;
+	}
+	client.station = station.to_string();
 
-		match request {
-			Request::Play(settings) => {
-				if s.write_all(&rmp_serde::to_vec_named(&check_settings(&settings)).unwrap())
-					.is_err()
-				{
-					continue;
-				}
-				tokio::spawn(stream(s, tracklist.clone(), settings));
-			}
-			Request::ListPlaylist => match playlists {
-				None => {
-					s.write_all(
-						&rmp_serde::to_vec_named(&RequestResult::Playlist(
-							lonelyradio_types::PlaylistResponce {
-								playlists: vec![],
-							},
-						))
-						.unwrap(),
-					)
-					.unwrap();
-				}
-				Some(ref playlists) => {
-					s.write_all(
-						&rmp_serde::to_vec_named(&RequestResult::Playlist(
-							lonelyradio_types::PlaylistResponce {
-								playlists: playlists.keys().cloned().collect(),
-							},
-						))
-						.unwrap(),
-					)
-					.unwrap();
-				}
-			},
+	match request {
+		Request::Play(settings) => {
+			start_stream(
+				s,
+				catalog.clone(),
+				TrackSource::shuffle(tracklist.clone()),
+				settings,
+				client,
+				key,
+				slot,
+				session,
+			);
+		}
+		Request::ListPlaylist => {
+			let _ = s.write_all(
+				&rmp_serde::to_vec_named(&RequestResult::Playlist(
+					lonelyradio_types::PlaylistResponce {
+						playlists: catalog
+							.playlists
+							.keys()
+							.filter(|x| client.access.allows(x))
+							.cloned()
+							.collect(),
+					},
+				))
+				.unwrap(),
+			);
+		}
 
-			Request::PlayPlaylist(playlist, settings) => {
-				if playlists.is_none() || playlists.as_ref().unwrap().get(&playlist).is_none() {
-					s.write_all(
-						&rmp_serde::to_vec_named(&RequestResult::Error(
-							lonelyradio_types::RequestError::NoSuchPlaylist,
-						))
-						.unwrap(),
-					)
-					.unwrap();
-					continue;
-				}
-				if s.write_all(&rmp_serde::to_vec_named(&check_settings(&settings)).unwrap())
-					.is_err()
-				{
-					continue;
-				}
-				let tracklist = playlists.as_ref().unwrap().get(&playlist).unwrap().clone();
-				tokio::spawn(stream(s, tracklist, settings));
+		Request::PlayPlaylist(playlist, settings) => {
+			let Some(tracklist) = catalog.playlists.get(&playlist).cloned() else {
+				send_error(s, RequestError::NoSuchPlaylist);
+				return;
+			};
+			start_stream(
+				s,
+				catalog.clone(),
+				TrackSource::shuffle(tracklist),
+				settings,
+				client,
+				key,
+				slot,
+				session,
+			);
+		}
+		Request::Browse(browse, page) => {
+			let _ = s.write_all(
+				&rmp_serde::to_vec_named(&RequestResult::Library(library.browse(&browse, page)))
+					.unwrap(),
+			);
+		}
+		Request::Search(query, page) => {
+			let _ = s.write_all(
+				&rmp_serde::to_vec_named(&RequestResult::Library(library.search(&query, page)))
+					.unwrap(),
+			);
+		}
+		Request::PlayTrack(id, settings) => match library.get(id) {
+			None => send_error(s, RequestError::NoSuchTrack),
+			Some(track) => start_stream(
+				s,
+				catalog.clone(),
+				TrackSource::queue(vec![track.path.clone()], tracklist.clone()),
+				settings,
+				client,
+				key,
+				slot,
+				session,
+			),
+		},
+		Request::PlayAlbum(artist, album, settings) => {
+			match library.album(&artist, &album).as_slice() {
+				[] => send_error(s, RequestError::NoSuchAlbum),
+				tracks => start_stream(
+					s,
+					catalog.clone(),
+					TrackSource::queue(
+						tracks.iter().map(|x| x.path.clone()).collect(),
+						tracklist.clone(),
+					),
+					settings,
+					client,
+					key,
+					slot,
+					session,
+				),
 			}
 		}
+		Request::PlayArtist(artist, settings) => {
+			let mut tracks: Vec<PathBuf> =
+				library.artist(&artist).iter().map(|x| x.path.clone()).collect();
+			if tracks.is_empty() {
+				send_error(s, RequestError::NoSuchArtist);
+				return;
+			}
+			tracks.shuffle(&mut thread_rng());
+			start_stream(
+				s,
+				catalog.clone(),
+				TrackSource::queue(tracks, tracklist.clone()),
+				settings,
+				client,
+				key,
+				slot,
+				session,
+			);
+		}
+		Request::Status => {
+			let _ = s.write_all(
+				&rmp_serde::to_vec_named(&RequestResult::Status(status::status(
+					&client.access,
+					metrics::listeners(),
+				)))
+				.unwrap(),
+			);
+		}
+		// Only once, before the actual request
+		Request::Authenticate(_) => send_error(s, RequestError::Unauthorized),
 	}
 }
 
-fn check_settings(settings: &Settings) -> RequestResult {
+/// Negotiated protocol version, None if it's not a lonelyradio client
+fn hello(s: &mut impl Read) -> std::io::Result<Option<u16>> {
+	let mut hello = [0u8; 8];
+	s.read_exact(&mut hello)?;
+	if &hello == lonelyradio_types::HELLO_MAGIC {
+		Ok(Some(0))
+	} else if &hello == lonelyradio_types::HELLO_MAGIC_VERSIONED {
+		let mut version = [0u8; 2];
+		s.read_exact(&mut version)?;
+		Ok(Some(u16::from_le_bytes(version).min(PROTOCOL_VERSION)))
+	} else {
+		Ok(None)
+	}
+}
+
+fn capabilities(version: u16, access: &Access) -> ServerCapabilities {
+	let mut available_requests = vec![
+		RequestKind::Play,
+		RequestKind::ListPlaylist,
+		RequestKind::PlayPlaylist,
+		RequestKind::Browse,
+		RequestKind::Search,
+		RequestKind::PlayTrack,
+		RequestKind::PlayAlbum,
+		RequestKind::PlayArtist,
+		RequestKind::Status,
+	];
+	if access.challenge().is_some() {
+		available_requests.push(RequestKind::Authenticate);
+	}
+	let mut features = vec![Feature::Control, Feature::Framing];
+	if !KEYS.is_empty() {
+		features.push(Feature::Encryption);
+	}
+	if Args::parse().artwork != -1 {
+		features.push(Feature::Cover);
+	}
+	ServerCapabilities {
+		encoders: encoders(),
+		max_bandwidth: Args::parse().max_bandwidth,
+		version,
+		available_requests,
+		features,
+		station: STATION.clone(),
+		challenge: access.challenge(),
+	}
+}
+
+/// Checks `networks` rules for every station on connection
+fn accepts(ip: Option<IpAddr>) -> bool {
+	let allowed = network_allows("*", ip);
+	if !allowed {
+		warn!(peer = ip.map(tracing::field::display), "rejected connection");
+		metrics::rejected("network");
+	}
+	allowed
+}
+
+/// Counts a connection against listener limits before anything is read from
+/// it, so idle connections can't pile up past them. Connections over the
+/// limits are closed
+fn acquire(ip: Option<IpAddr>) -> Option<Slot> {
+	let slot = Slot::acquire(ip);
+	if slot.is_none() {
+		warn!(peer = ip.map(tracing::field::display), "Listener limits reached");
+		metrics::rejected("busy");
+	}
+	slot
+}
+
+fn send_error(mut s: impl Write, error: RequestError) {
+	let _ = s.write_all(&rmp_serde::to_vec_named(&RequestResult::Error(error)).unwrap());
+}
+
+/// Responds with `check_settings()` result and starts streaming if settings are
+/// fine. Stations with a key are encrypted
+#[allow(clippy::too_many_arguments)]
+fn start_stream(
+	mut s: impl Transport,
+	catalog: Arc<Catalog>,
+	source: TrackSource,
+	settings: Settings,
+	client: Client,
+	key: Option<&'static [u8]>,
+	slot: Slot,
+	session: Session,
+) {
+	let mut result = check_settings(&settings, &encoders());
+	let mut output = Output::new(&settings, key, s.lead(), slot, session);
+	if let (RequestResult::Ok, Some(key)) = (&result, key) {
+		result = if settings.encryption {
+			let mut salt = vec![0u8; 16];
+			thread_rng().fill_bytes(&mut salt);
+			output.cipher = Some(Cipher::new(key, &salt));
+			RequestResult::Sealed(salt)
+		} else {
+			RequestResult::Error(RequestError::EncryptionRequired)
+		};
+	}
+	if let RequestResult::Error(e) = &result {
+		metrics::rejected(match e {
+			RequestError::EncryptionRequired => "encryption",
+			_ => "settings",
+		});
+	}
+	if s.write_all(&rmp_serde::to_vec_named(&result).unwrap()).is_err()
+		|| matches!(result, RequestResult::Error(_))
+	{
+		return;
+	}
+	// Clients stop reading while their buffers are full
+	if s.set_timeout(None).is_err() {
+		return;
+	}
+	tokio::spawn(stream(s, catalog, source, settings, client, output).instrument(Span::current()));
+}
+
+fn check_settings(settings: &Settings, encoders: &[Encoder]) -> RequestResult {
 	if settings.cover < -1 {
 		return RequestResult::Error(lonelyradio_types::RequestError::WrongCoverSize);
 	}
-	if !SUPPORTED_ENCODERS.contains(&settings.encoder) {
+	// Including ones that can't fit into --max-bandwidth, they would never play
+	// in real time
+	if !encoders.contains(&settings.encoder) {
 		return RequestResult::Error(lonelyradio_types::RequestError::UnsupportedEncoder);
 	}
 	RequestResult::Ok
@@ -307,17 +1049,19 @@ struct Metadata {
 }
 
 fn get_metadata(track: impl AsRef<Path>, args: &Args, settings: &Settings) -> Option<Metadata> {
-	let mut title = String::new();
+	// Playlist titles take precedence over file names, not over tags
+	let mut title = playlist::title(track.as_ref()).unwrap_or_default();
 	let mut artist = String::new();
 	let mut album = String::new();
 	let mut cover = std::thread::spawn(|| None);
 	let mut file = std::fs::File::open(&track).unwrap();
 	let tagged = lofty::read_from(&mut file).ok()?;
 	if let Some(id3v2) = tagged.primary_tag() {
-		title = id3v2
-			.title()
-			.unwrap_or(track.as_ref().file_stem().unwrap().to_string_lossy())
-			.to_string();
+		title = match id3v2.title() {
+			Some(t) => t.to_string(),
+			None if !title.is_empty() => title,
+			None => track.as_ref().file_stem().unwrap().to_string_lossy().to_string(),
+		};
 		album = id3v2.album().unwrap_or("".into()).to_string();
 		artist = id3v2.artist().unwrap_or("".into()).to_string();
 		if !(id3v2.pictures().is_empty() || args.artwork == -1 || settings.cover == -1) {
@@ -349,15 +1093,40 @@ fn get_metadata(track: impl AsRef<Path>, args: &Args, settings: &Settings) -> Op
 	})
 }
 
-async fn stream(mut s: impl Write, tracklist: Arc<Vec<PathBuf>>, settings: Settings) {
+async fn stream(
+	mut s: impl Transport,
+	catalog: Arc<Catalog>,
+	mut source: TrackSource,
+	mut settings: Settings,
+	mut client: Client,
+	mut output: Output,
+) {
 	let args = Args::parse();
-	let encoder_wants = match settings.encoder {
-		Encoder::Opus | Encoder::Vorbis | Encoder::Aac => 48000,
-		Encoder::Flac => 96000,
-		_ => 0,
-	};
+	// Older clients can't parse notices
+	let mut notices = (client.version >= 2).then(|| NOTICES.subscribe());
+	// Track to be played again (after Replay or ChangeEncoder) and position in
+	// seconds to start from
+	let mut resume: Option<(PathBuf, f64)> = None;
+	let mut sequence = 0;
+	let _running = shutdown::Running::new();
+	let mut _listening = metrics::Listening::new(&client.station, settings.encoder);
 	loop {
-		let track = tracklist.choose(&mut thread_rng()).unwrap().clone();
+		if shutdown::stopping() {
+			return;
+		}
+		let encoder_wants = match settings.encoder {
+			Encoder::Opus | Encoder::Vorbis | Encoder::Aac => 48000,
+			Encoder::Flac => 96000,
+			_ => 0,
+		};
+		let resumed = resume.is_some();
+		let (track, position) = match resume.take() {
+			Some(r) => r,
+			None => match source.next() {
+				Some(track) => (track, 0.0),
+				None => return,
+			},
+		};
 
 		let Metadata {
 			title,
@@ -366,16 +1135,24 @@ async fn stream(mut s: impl Write, tracklist: Arc<Vec<PathBuf>>, settings: Setti
 			cover,
 		} = match get_metadata(&track, &args, &settings) {
 			Some(m) => m,
-			_ => continue,
+			_ => {
+				warn!(?track, "Failed to read tags");
+				continue;
+			}
 		};
 
-		let track_message = format!("{} - {} - {}", &artist, &album, &title);
-		println!("[{}] {} ({:?})", Local::now().to_rfc3339(), track_message, settings.encoder);
-
 		let (channels, sample_rate, time) = get_meta(track.as_path(), encoder_wants);
-		let stream = decode_file_stream(track, encoder_wants);
-		let id = thread_rng().gen();
-		if stream_track(
+		let track_id = library::track_id(&args.dir, &track);
+		// Replayed or re-encoded track isn't played again
+		if !resumed {
+			output.session.tracks += 1;
+			metrics::played(&client.station);
+			status::played(&client.station, track_id, &artist, &album, &title);
+			info!(%artist, %album, %title, encoder = ?settings.encoder, "playing");
+		}
+		let stream = decode_file_stream(track.clone(), encoder_wants);
+		let skip = (position * sample_rate as f64) as usize * channels as usize;
+		let outcome = stream_track(
 			stream,
 			TrackMetadata {
 				track_length_frac: time.frac as f32,
@@ -386,7 +1163,8 @@ async fn stream(mut s: impl Write, tracklist: Arc<Vec<PathBuf>>, settings: Setti
 					x.write_to(&mut buf, image::ImageFormat::Jpeg).unwrap();
 					buf.into_inner()
 				}),
-				id,
+				id: track_id as u8,
+				track_id,
 				album,
 				artist,
 				title,
@@ -394,10 +1172,226 @@ async fn stream(mut s: impl Write, tracklist: Arc<Vec<PathBuf>>, settings: Setti
 				channels,
 			},
 			&mut s,
+			skip,
+			&mut output,
+			&mut sequence,
+			&mut notices,
 		)
-		.await
-		{
-			return;
+		.await;
+		match outcome {
+			Outcome::Finished => {}
+			Outcome::Disconnected => return,
+			Outcome::Control(control, sent) => {
+				info!(?control, "control");
+				let position = sent as f64 / sample_rate as f64 / channels as f64;
+				// Buffered audio is dropped by the client
+				if !matches!(control, Control::ChangeEncoder(_)) {
+					output.reset_pacing();
+				}
+				let before = (client.station.clone(), settings.encoder);
+				let then = apply_control(
+					control,
+					&mut source,
+					&mut settings,
+					&mut client,
+					&catalog,
+					output.key,
+					&encoders(),
+				);
+				if before != (client.station.clone(), settings.encoder) {
+					_listening = metrics::Listening::new(&client.station, settings.encoder);
+				}
+				match then {
+					Then::Next => {}
+					Then::Replay => resume = Some((track, 0.0)),
+					Then::Continue => resume = Some((track, position)),
+					Then::Switched => {
+						let notice = Notice::Station(client.station.clone());
+						if notices.is_some() && !send_notice(&mut s, &mut output, notice) {
+							return;
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+/// What happens to the current track after a Control message
+#[derive(Debug, PartialEq)]
+enum Then {
+	Next,
+	Replay,
+	/// From the same position
+	Continue,
+	/// Next track is from `client.station`
+	Switched,
+}
+
+fn apply_control(
+	control: Control,
+	source: &mut TrackSource,
+	settings: &mut Settings,
+	client: &mut Client,
+	catalog: &Catalog,
+	key: Option<&[u8]>,
+	encoders: &[Encoder],
+) -> Then {
+	match control {
+		Control::Skip => Then::Next,
+		Control::Replay => Then::Replay,
+		// Same checks as on connection, refused encoder is ignored
+		Control::ChangeEncoder(encoder) => {
+			let changed = Settings {
+				encoder,
+				..settings.clone()
+			};
+			if check_settings(&changed, encoders) == RequestResult::Ok {
+				*settings = changed;
+			}
+			Then::Continue
+		}
+		// Unknown, private or differently encrypted playlist, just continue
+		Control::ChangePlaylist(playlist) => match catalog.playlist(&playlist) {
+			Some(tracklist) if client.access.allows(&playlist) && station_key(&playlist) == key => {
+				*source = TrackSource::shuffle(tracklist);
+				client.station = playlist;
+				Then::Switched
+			}
+			_ => Then::Continue,
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{TcpListener, TcpStream};
+
+	use super::*;
+
+	fn pair() -> (TcpStream, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		(client, listener.accept().unwrap().0)
+	}
+
+	#[test]
+	fn hellos() {
+		let versioned = |version: u16| [&b"lonelyrv"[..], &version.to_le_bytes()].concat();
+		// Legacy clients don't send a version
+		assert_eq!(hello(&mut &b"lonelyra"[..]).unwrap(), Some(0));
+		assert_eq!(hello(&mut &versioned(0)[..]).unwrap(), Some(0));
+		assert_eq!(hello(&mut &versioned(1)[..]).unwrap(), Some(1));
+		assert_eq!(hello(&mut &versioned(PROTOCOL_VERSION)[..]).unwrap(), Some(PROTOCOL_VERSION));
+		// Newer clients get the server's version
+		assert_eq!(hello(&mut &versioned(u16::MAX)[..]).unwrap(), Some(PROTOCOL_VERSION));
+		assert_eq!(hello(&mut &b"GET / HTTP/1.1\r\n"[..]).unwrap(), None);
+		assert!(hello(&mut &b"lonelyrv\x01"[..]).is_err());
+		assert!(hello(&mut &b"lonely"[..]).is_err());
+	}
+
+	#[test]
+	fn controls() {
+		let catalog = Catalog {
+			tracklist: Arc::new(vec![PathBuf::from("/library")]),
+			playlists: HashMap::from([(
+				"jazz".to_string(),
+				Arc::new(vec![PathBuf::from("/jazz")]),
+			)]),
+		};
+		let mut source = TrackSource::shuffle(catalog.tracklist.clone());
+		let mut settings = Settings {
+			encoder: Encoder::Pcm16,
+			cover: -1,
+			framing: None,
+			encryption: false,
 		};
+		let mut client = Client {
+			version: PROTOCOL_VERSION,
+			access: Access::new(None),
+			station: String::new(),
+		};
+		let encoders = [Encoder::Pcm16, Encoder::PcmFloat];
+		let mut apply = |control, key: Option<&[u8]>| {
+			let then = apply_control(
+				control,
+				&mut source,
+				&mut settings,
+				&mut client,
+				&catalog,
+				key,
+				&encoders,
+			);
+			(then, source.next().unwrap(), settings.encoder, client.station.clone())
+		};
+		let library = PathBuf::from("/library");
+		let jazz = PathBuf::from("/jazz");
+
+		assert_eq!(
+			apply(Control::Skip, None),
+			(Then::Next, library.clone(), Encoder::Pcm16, "".into())
+		);
+		assert_eq!(
+			apply(Control::Replay, None),
+			(Then::Replay, library.clone(), Encoder::Pcm16, "".into())
+		);
+		assert_eq!(
+			apply(Control::ChangeEncoder(Encoder::PcmFloat), None),
+			(Then::Continue, library.clone(), Encoder::PcmFloat, "".into())
+		);
+		// Not offered, ignored
+		assert_eq!(
+			apply(Control::ChangeEncoder(Encoder::Flac), None),
+			(Then::Continue, library.clone(), Encoder::PcmFloat, "".into())
+		);
+		assert_eq!(
+			apply(Control::ChangePlaylist("jazz".into()), None),
+			(Then::Switched, jazz.clone(), Encoder::PcmFloat, "jazz".into())
+		);
+		assert_eq!(
+			apply(Control::ChangePlaylist("rock".into()), None),
+			(Then::Continue, jazz.clone(), Encoder::PcmFloat, "jazz".into())
+		);
+		// Encrypted stream can't switch to an unencrypted station
+		assert_eq!(
+			apply(Control::ChangePlaylist("".into()), Some(b"key")),
+			(Then::Continue, jazz.clone(), Encoder::PcmFloat, "jazz".into())
+		);
+		assert_eq!(
+			apply(Control::ChangePlaylist("".into()), None),
+			(Then::Switched, library.clone(), Encoder::PcmFloat, "".into())
+		);
+	}
+
+	#[test]
+	fn control_messages() {
+		let (mut client, mut server) = pair();
+		for control in [Control::Skip, Control::ChangePlaylist("jazz".into())] {
+			client.write_all(&rmp_serde::to_vec_named(&control).unwrap()).unwrap();
+			assert_eq!(read_control(&mut server), Some(control));
+		}
+		// Not a Control message
+		client.write_all(&rmp_serde::to_vec_named(&Request::Status).unwrap()).unwrap();
+		assert_eq!(read_control(&mut server), None);
+
+		let (mut client, mut server) = pair();
+		let oversized = Control::ChangePlaylist("x".repeat(MAX_CONTROL as usize));
+		client.write_all(&rmp_serde::to_vec_named(&oversized).unwrap()).unwrap();
+		assert_eq!(read_control(&mut server), None);
+
+		let (client, mut server) = pair();
+		drop(client);
+		assert_eq!(read_control(&mut server), None);
+	}
+
+	#[test]
+	fn partial_control_times_out() {
+		let (mut client, mut server) = pair();
+		let control = rmp_serde::to_vec_named(&Control::ChangePlaylist("jazz".into())).unwrap();
+		client.write_all(&control[..control.len() - 1]).unwrap();
+		let start = Instant::now();
+		assert_eq!(read_control(&mut server), None);
+		assert!(start.elapsed() >= CONTROL_TIMEOUT);
+		assert!(start.elapsed() < CONTROL_TIMEOUT * 2);
 	}
 }
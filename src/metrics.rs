@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lonelyradio_types::Encoder;
+use once_cell::sync::Lazy;
+
+use crate::web::peek_head;
+
+/// Upper bounds of encode time buckets in seconds
+const BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static DECODE_FAILURES: AtomicU64 = AtomicU64::new(0);
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct Metrics {
+	/// (station, encoder) -> listeners
+	listeners: BTreeMap<(String, String), u64>,
+	/// Encoder -> fragments
+	fragments: BTreeMap<String, u64>,
+	encode_seconds: BTreeMap<String, Histogram>,
+	/// Station -> tracks
+	plays: BTreeMap<String, u64>,
+	/// Reason -> clients
+	rejections: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Default)]
+struct Histogram {
+	/// Observations not larger than the matching bucket bound
+	buckets: [u64; BUCKETS.len()],
+	sum: f64,
+	count: u64,
+}
+
+impl Histogram {
+	fn observe(&mut self, seconds: f64) {
+		for (count, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+			if seconds <= *bound {
+				*count += 1;
+			}
+		}
+		self.sum += seconds;
+		self.count += 1;
+	}
+}
+
+pub fn bytes_sent(bytes: usize) {
+	BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn decode_failed() {
+	DECODE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// One `encode()` call producing `fragments` fragments
+pub fn encoded(encoder: Encoder, time: Duration, fragments: usize) {
+	let encoder = format!("{:?}", encoder);
+	let mut metrics = METRICS.lock().unwrap();
+	*metrics.fragments.entry(encoder.clone()).or_default() += fragments as u64;
+	metrics.encode_seconds.entry(encoder).or_default().observe(time.as_secs_f64());
+}
+
+pub fn played(station: &str) {
+	*METRICS.lock().unwrap().plays.entry(station.to_string()).or_default() += 1;
+}
+
+/// Client rejected during the handshake
+pub fn rejected(reason: &'static str) {
+	*METRICS.lock().unwrap().rejections.entry(reason).or_default() += 1;
+}
+
+/// Counts a listener of the station with the encoder while alive
+pub struct Listening {
+	key: (String, String),
+}
+
+impl Listening {
+	pub fn new(station: &str, encoder: Encoder) -> Self {
+		let key = (station.to_string(), format!("{:?}", encoder));
+		*METRICS.lock().unwrap().listeners.entry(key.clone()).or_default() += 1;
+		Self {
+			key,
+		}
+	}
+}
+
+impl Drop for Listening {
+	fn drop(&mut self) {
+		let listeners = &mut METRICS.lock().unwrap().listeners;
+		if let Some(count) = listeners.get_mut(&self.key) {
+			*count -= 1;
+			// Stations nobody listens to aren't reported
+			if *count == 0 {
+				listeners.remove(&self.key);
+			}
+		}
+	}
+}
+
+/// Station -> listeners with any encoder
+pub fn listeners() -> BTreeMap<String, u64> {
+	let mut listeners = BTreeMap::new();
+	for ((station, _), count) in &METRICS.lock().unwrap().listeners {
+		*listeners.entry(station.clone()).or_default() += count;
+	}
+	listeners
+}
+
+/// Metrics in Prometheus text format
+fn render() -> String {
+	METRICS
+		.lock()
+		.unwrap()
+		.render(BYTES_SENT.load(Ordering::Relaxed), DECODE_FAILURES.load(Ordering::Relaxed))
+}
+
+impl Metrics {
+	fn render(&self, bytes_sent: u64, decode_failures: u64) -> String {
+		let mut out = String::new();
+		let _ = writeln!(out, "# HELP lonelyradio_listeners Listeners by station and encoder");
+		let _ = writeln!(out, "# TYPE lonelyradio_listeners gauge");
+		for ((station, encoder), count) in &self.listeners {
+			let _ = writeln!(
+				out,
+				"lonelyradio_listeners{{station=\"{}\",encoder=\"{}\"}} {}",
+				escape(station),
+				encoder,
+				count
+			);
+		}
+		let _ = writeln!(
+			out,
+			"# HELP lonelyradio_bytes_sent_total Bytes of messages sent to listeners"
+		);
+		let _ = writeln!(out, "# TYPE lonelyradio_bytes_sent_total counter");
+		let _ = writeln!(out, "lonelyradio_bytes_sent_total {}", bytes_sent);
+		let _ = writeln!(out, "# HELP lonelyradio_fragments_encoded_total Fragments encoded");
+		let _ = writeln!(out, "# TYPE lonelyradio_fragments_encoded_total counter");
+		for (encoder, count) in &self.fragments {
+			let _ = writeln!(
+				out,
+				"lonelyradio_fragments_encoded_total{{encoder=\"{}\"}} {}",
+				encoder, count
+			);
+		}
+		let _ =
+			writeln!(out, "# HELP lonelyradio_encode_seconds Time of encoding a chunk of samples");
+		let _ = writeln!(out, "# TYPE lonelyradio_encode_seconds histogram");
+		for (encoder, histogram) in &self.encode_seconds {
+			for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+				let _ = writeln!(
+					out,
+					"lonelyradio_encode_seconds_bucket{{encoder=\"{}\",le=\"{}\"}} {}",
+					encoder, bound, count
+				);
+			}
+			let _ = writeln!(
+				out,
+				"lonelyradio_encode_seconds_bucket{{encoder=\"{}\",le=\"+Inf\"}} {}",
+				encoder, histogram.count
+			);
+			let _ = writeln!(
+				out,
+				"lonelyradio_encode_seconds_sum{{encoder=\"{}\"}} {}",
+				encoder, histogram.sum
+			);
+			let _ = writeln!(
+				out,
+				"lonelyradio_encode_seconds_count{{encoder=\"{}\"}} {}",
+				encoder, histogram.count
+			);
+		}
+		let _ = writeln!(out, "# HELP lonelyradio_decode_failures_total Packets failed to decode");
+		let _ = writeln!(out, "# TYPE lonelyradio_decode_failures_total counter");
+		let _ = writeln!(out, "lonelyradio_decode_failures_total {}", decode_failures);
+		let _ = writeln!(out, "# HELP lonelyradio_track_plays_total Tracks started by station");
+		let _ = writeln!(out, "# TYPE lonelyradio_track_plays_total counter");
+		for (station, count) in &self.plays {
+			let _ = writeln!(
+				out,
+				"lonelyradio_track_plays_total{{station=\"{}\"}} {}",
+				escape(station),
+				count
+			);
+		}
+		let _ = writeln!(out, "# HELP lonelyradio_rejections_total Clients rejected by reason");
+		let _ = writeln!(out, "# TYPE lonelyradio_rejections_total counter");
+		for (reason, count) in &self.rejections {
+			let _ =
+				writeln!(out, "lonelyradio_rejections_total{{reason=\"{}\"}} {}", reason, count);
+		}
+		out
+	}
+}
+
+fn escape(label: &str) -> String {
+	label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Responds to "GET /metrics"
+pub fn serve(mut stream: TcpStream) {
+	let Some((head, length)) = peek_head(&stream) else {
+		return;
+	};
+	if stream.read_exact(&mut vec![0u8; length]).is_err() {
+		return;
+	}
+	let response = match head.split_whitespace().nth(1) {
+		Some("/metrics") => {
+			let body = render();
+			format!(
+				"HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+				 {}\r\nConnection: close\r\n\r\n{}",
+				body.len(),
+				body
+			)
+		}
+		_ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+	};
+	let _ = stream.write_all(response.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buckets() {
+		let mut histogram = Histogram::default();
+		histogram.observe(0.0005);
+		histogram.observe(0.01);
+		histogram.observe(0.3);
+		histogram.observe(10.0);
+		// Cumulative, bounds are inclusive
+		assert_eq!(histogram.buckets, [1, 1, 1, 2, 2, 2, 2, 2, 3, 3, 3]);
+		assert_eq!(histogram.count, 4);
+		assert!((histogram.sum - 10.3105).abs() < 1e-9);
+	}
+
+	#[test]
+	fn rendered() {
+		let mut metrics = Metrics::default();
+		metrics.listeners.insert(("late \"night\"".to_string(), "Flac".to_string()), 2);
+		metrics.fragments.insert("Flac".to_string(), 7);
+		metrics.encode_seconds.entry("Flac".to_string()).or_default().observe(0.02);
+		metrics.plays.insert(String::new(), 3);
+		metrics.rejections.insert("busy", 1);
+		let out = metrics.render(1024, 5);
+		let lines: Vec<&str> = out.lines().collect();
+		for line in [
+			"# TYPE lonelyradio_listeners gauge",
+			"lonelyradio_listeners{station=\"late \\\"night\\\"\",encoder=\"Flac\"} 2",
+			"lonelyradio_bytes_sent_total 1024",
+			"lonelyradio_fragments_encoded_total{encoder=\"Flac\"} 7",
+			"# TYPE lonelyradio_encode_seconds histogram",
+			"lonelyradio_encode_seconds_bucket{encoder=\"Flac\",le=\"0.01\"} 0",
+			"lonelyradio_encode_seconds_bucket{encoder=\"Flac\",le=\"0.025\"} 1",
+			"lonelyradio_encode_seconds_bucket{encoder=\"Flac\",le=\"+Inf\"} 1",
+			"lonelyradio_encode_seconds_sum{encoder=\"Flac\"} 0.02",
+			"lonelyradio_encode_seconds_count{encoder=\"Flac\"} 1",
+			"lonelyradio_decode_failures_total 5",
+			"lonelyradio_track_plays_total{station=\"\"} 3",
+			"lonelyradio_rejections_total{reason=\"busy\"} 1",
+		] {
+			assert!(lines.contains(&line), "{:?} not in\n{}", line, out);
+		}
+		// Every sample is preceded by its HELP and TYPE
+		assert_eq!(lines.iter().filter(|x| x.starts_with("# TYPE")).count(), 7);
+		assert!(lines.iter().all(|x| x.starts_with('#') || x.starts_with("lonelyradio_")));
+	}
+
+	#[test]
+	fn listeners_are_removed() {
+		let station = "metrics test station";
+		let first = Listening::new(station, Encoder::Pcm16);
+		let second = Listening::new(station, Encoder::Pcm16);
+		let third = Listening::new(station, Encoder::Flac);
+		assert_eq!(listeners().get(station), Some(&3));
+		drop((first, third));
+		assert_eq!(listeners().get(station), Some(&1));
+		drop(second);
+		assert_eq!(listeners().get(station), None);
+		assert!(!render().contains(station));
+	}
+}
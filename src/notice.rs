@@ -0,0 +1,56 @@
+use std::io::BufRead;
+
+use lonelyradio_types::Notice;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Notices for every listener with protocol version 2 or newer
+pub static NOTICES: Lazy<broadcast::Sender<Notice>> = Lazy::new(|| broadcast::channel(16).0);
+
+pub fn announce(notice: Notice) {
+	info!(%notice, "announced");
+	// Nobody is listening, that's fine
+	let _ = NOTICES.send(notice);
+}
+
+/// Announces every non-empty line of stdin
+pub fn read_announcements() {
+	std::thread::spawn(|| announcements(std::io::stdin().lock()).for_each(announce));
+}
+
+/// Trimmed non-empty lines as announcements
+fn announcements(input: impl BufRead) -> impl Iterator<Item = Notice> {
+	input
+		.lines()
+		.map_while(Result::ok)
+		.filter(|x| !x.trim().is_empty())
+		.map(|x| Notice::Announcement(x.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lines() {
+		let input = "Back in 5 minutes\n\n   \n  Requests are open \r\nlast".as_bytes();
+		assert_eq!(
+			announcements(input).collect::<Vec<_>>(),
+			["Back in 5 minutes", "Requests are open", "last"]
+				.map(|x| Notice::Announcement(x.to_string()))
+		);
+	}
+
+	#[test]
+	fn every_listener_gets_it() {
+		let mut listeners = [NOTICES.subscribe(), NOTICES.subscribe()];
+		let notice = Notice::Announcement("notice test".to_string());
+		announce(notice.clone());
+		for listener in listeners.iter_mut() {
+			assert_eq!(listener.try_recv().unwrap(), notice);
+		}
+		// Late listeners don't get earlier notices
+		assert!(NOTICES.subscribe().try_recv().is_err());
+	}
+}
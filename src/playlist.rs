@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use clap::Parser;
+use once_cell::sync::Lazy;
+use quick_xml::events::Event;
+use tracing::warn;
+use url::Url;
+use xspf::Playlist;
+
+use crate::library::TrackInfo;
+use crate::query::Query;
+use crate::{is_not_hidden, track_valid, Args};
+
+/// Track titles given by playlists (`#EXTINF`)
+static TITLES: Lazy<RwLock<HashMap<PathBuf, String>>> = Lazy::new(Default::default);
+
+/// Title of the track given by a playlist, used when the file has none
+pub fn title(track: &Path) -> Option<String> {
+	TITLES.read().unwrap().get(track).cloned()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+	Xspf,
+	M3u,
+	Pls,
+}
+
+impl Format {
+	fn detect(path: &Path, content: &str) -> Option<Self> {
+		match path.extension().and_then(|x| x.to_str()).map(|x| x.to_lowercase()).as_deref() {
+			Some("xspf") => return Some(Self::Xspf),
+			Some("m3u" | "m3u8") => return Some(Self::M3u),
+			Some("pls") => return Some(Self::Pls),
+			_ => {}
+		}
+		// Unknown extension, looking at the content instead
+		let head = content.trim_start_matches('\u{feff}').trim_start();
+		if head.starts_with("<?xml") || head.starts_with("<playlist") {
+			Some(Self::Xspf)
+		} else if head.starts_with("#EXTM3U") {
+			Some(Self::M3u)
+		} else if head.to_lowercase().starts_with("[playlist]") {
+			Some(Self::Pls)
+		} else {
+			None
+		}
+	}
+}
+
+pub fn get_playlists(dir: impl AsRef<Path>) -> Option<HashMap<String, Arc<Vec<PathBuf>>>> {
+	let args = Args::parse();
+	let mut map: HashMap<String, Arc<Vec<PathBuf>>> = HashMap::new();
+	for playlist in walkdir::WalkDir::new(dir)
+		.into_iter()
+		.filter_entry(is_not_hidden)
+		.filter_map(|v| v.ok())
+		.map(|x| x.into_path())
+		.filter(|x| x.is_file())
+	{
+		let (title, entries) = match read_playlist(&playlist) {
+			Ok(Some(p)) => p,
+			Ok(None) => continue,
+			Err(e) => {
+				warn!("Skipping playlist {:?}: {}", playlist, e);
+				continue;
+			}
+		};
+		let name =
+			title.unwrap_or_else(|| playlist.file_name().unwrap().to_string_lossy().to_string());
+		let mut tracklist = vec![];
+		let mut unresolved = vec![];
+		for (entry, title) in entries {
+			match entry.map(|x| remap(x, &args.remap)) {
+				Ok(track) if track_valid(&track) => {
+					if let Some(title) = title {
+						TITLES.write().unwrap().insert(track.clone(), title);
+					}
+					tracklist.push(track)
+				}
+				Ok(track) => unresolved.push(track.to_string_lossy().to_string()),
+				Err(location) => unresolved.push(location),
+			}
+		}
+		if !unresolved.is_empty() {
+			warn!(
+				?unresolved,
+				"Playlist \"{}\" ({:?}): {} of {} entries could not be resolved",
+				name,
+				playlist,
+				unresolved.len(),
+				unresolved.len() + tracklist.len()
+			);
+		}
+		map.insert(name, Arc::new(tracklist));
+	}
+	Some(map)
+}
+
+/// Playlists named after library subdirectories (relative to `root`) up to
+/// `depth` levels deep, each containing all tracks below it
+pub fn get_dir_playlists(
+	root: &Path,
+	tracklist: &[PathBuf],
+	depth: usize,
+) -> HashMap<String, Arc<Vec<PathBuf>>> {
+	let mut map: HashMap<String, Vec<PathBuf>> = HashMap::new();
+	for track in tracklist {
+		let Some(dir) = track.strip_prefix(root).ok().and_then(|x| x.parent()) else {
+			continue;
+		};
+		let mut name = PathBuf::new();
+		for component in dir.components().take(depth) {
+			name.push(component);
+			map.entry(name.to_string_lossy().to_string()).or_default().push(track.clone());
+		}
+	}
+	map.into_iter().map(|(name, tracklist)| (name, Arc::new(tracklist))).collect()
+}
+
+/// Playlists of tracks matching tag queries (name -> query)
+pub fn get_smart_playlists(
+	library: &[TrackInfo],
+	queries: &HashMap<String, String>,
+) -> HashMap<String, Arc<Vec<PathBuf>>> {
+	let mut map = HashMap::new();
+	for (name, query) in queries {
+		let query = match Query::parse(query) {
+			Ok(q) => q,
+			Err(e) => {
+				warn!("Skipping smart playlist \"{}\": {}", name, e);
+				continue;
+			}
+		};
+		let tracklist: Vec<PathBuf> =
+			library.iter().filter(|x| query.matches(x)).map(|x| x.path.clone()).collect();
+		if tracklist.is_empty() {
+			warn!("Smart playlist \"{}\" matches no tracks", name);
+			continue;
+		}
+		map.insert(name.clone(), Arc::new(tracklist));
+	}
+	map
+}
+
+/// Parses `FROM=TO` path prefix replacement
+pub fn parse_remap(s: &str) -> Result<(PathBuf, PathBuf), String> {
+	match s.split_once('=') {
+		Some((from, to)) if !from.is_empty() => Ok((PathBuf::from(from), PathBuf::from(to))),
+		_ => Err(format!("expected FROM=TO, got \"{}\"", s)),
+	}
+}
+
+fn remap(track: PathBuf, rules: &[(PathBuf, PathBuf)]) -> PathBuf {
+	for (from, to) in rules {
+		if let Ok(rest) = track.strip_prefix(from) {
+			return to.join(rest);
+		}
+	}
+	track
+}
+
+/// Resolved entry or its original location if it could not be resolved
+type Entry = Result<PathBuf, String>;
+
+/// Playlist title (if it has one) and its entries with their titles
+type Parsed = (Option<String>, Vec<(Entry, Option<String>)>);
+
+/// `Ok(None)` means the file is not a playlist at all
+fn read_playlist(path: &Path) -> Result<Option<Parsed>, String> {
+	let raw = std::fs::read(path).map_err(|e| e.to_string())?;
+	// M3U is not required to be UTF-8, M3U8 and the rest are
+	let content = String::from_utf8_lossy(&raw);
+	let path = path.canonicalize().map_err(|e| e.to_string())?;
+	let base = Url::from_file_path(&path).map_err(|_| "invalid playlist path".to_string())?;
+	match Format::detect(&path, &content) {
+		None => Ok(None),
+		Some(Format::Xspf) => {
+			let parsed = Playlist::read_file(&path).map_err(|e| format!("{:?}", e))?;
+			// Relative locations are resolved against xml:base (which is itself
+			// relative to the playlist) and then against the playlist
+			let base = match xml_base(&content) {
+				Some(b) => base.join(&b).map_err(|e| format!("invalid xml:base: {}", e))?,
+				None => base,
+			};
+			let tracklist = parsed
+				.track_list
+				.iter()
+				.flat_map(|x| x.location.iter().map(|l| (resolve_url(l.as_str(), &base), None)))
+				.collect();
+			Ok(Some((parsed.title, tracklist)))
+		}
+		Some(Format::M3u) => Ok(Some(parse_m3u(&content, &base))),
+		Some(Format::Pls) => Ok(Some(parse_pls(&content, &base))),
+	}
+}
+
+/// Value of the xml:base attribute of the root element
+fn xml_base(content: &str) -> Option<String> {
+	let mut reader = quick_xml::Reader::from_str(content);
+	loop {
+		match reader.read_event().ok()? {
+			Event::Start(root) | Event::Empty(root) => {
+				return root
+					.attributes()
+					.filter_map(|x| x.ok())
+					.find(|x| x.key.as_ref() == b"xml:base")
+					.and_then(|x| x.unescape_value().ok())
+					.map(|x| x.into_owned())
+			}
+			Event::Eof => return None,
+			_ => {}
+		}
+	}
+}
+
+/// Resolves a URI reference (possibly relative and percent-encoded) against
+/// `base`. Only `file://` is supported
+fn resolve_url(location: &str, base: &Url) -> Entry {
+	let location = location.trim();
+	match base.join(location) {
+		Ok(url) if url.scheme() == "file" => url.to_file_path().map_err(|_| location.to_string()),
+		_ => Err(location.to_string()),
+	}
+}
+
+/// Resolves playlist entry, which may be an absolute path, a path relative to
+/// the playlist or a `file://` URL
+fn resolve_entry(entry: &str, base: &Url) -> Option<Entry> {
+	let entry = entry.trim();
+	if entry.is_empty() {
+		return None;
+	}
+	if let Ok(url) = Url::parse(entry) {
+		// Windows drive letters are parsed as URL schemes
+		if url.scheme().len() > 1 {
+			return Some(resolve_url(entry, base));
+		}
+	}
+	// Plain paths are not percent-encoded
+	let dir = base.to_file_path().ok().and_then(|x| x.parent().map(|x| x.to_path_buf()));
+	Some(dir.map(|x| x.join(entry)).ok_or_else(|| entry.to_string()))
+}
+
+fn parse_m3u(content: &str, base: &Url) -> Parsed {
+	let mut title = None;
+	let mut tracklist = vec![];
+	// From #EXTINF, applies to the next entry
+	let mut next = None;
+	for line in content.lines().map(|x| x.trim_start_matches('\u{feff}').trim()) {
+		if let Some(t) = line.strip_prefix("#PLAYLIST:") {
+			title = Some(t.trim().to_string());
+		} else if let Some(info) = line.strip_prefix("#EXTINF:") {
+			// #EXTINF:<length>[ <attributes>],<title>
+			next = info.split_once(',').map(|x| x.1.trim().to_string()).filter(|x| !x.is_empty());
+		} else if line.starts_with('#') {
+			continue;
+		} else if let Some(track) = resolve_entry(line, base) {
+			tracklist.push((track, next.take()));
+		}
+	}
+	(title, tracklist)
+}
+
+fn parse_pls(content: &str, base: &Url) -> Parsed {
+	let mut entries: Vec<(u32, Entry)> = vec![];
+	for line in content.lines().map(|x| x.trim()) {
+		let Some((key, value)) = line.split_once('=') else {
+			continue;
+		};
+		let key = key.trim().to_lowercase();
+		let Some(Ok(n)) = key.strip_prefix("file").map(|n| n.parse::<u32>()) else {
+			continue;
+		};
+		if let Some(track) = resolve_entry(value, base) {
+			entries.push((n, track));
+		}
+	}
+	entries.sort_by_key(|x| x.0);
+	(None, entries.into_iter().map(|x| (x.1, None)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn m3u_extinf_titles() {
+		let base = Url::parse("file:///music/list.m3u").unwrap();
+		let (title, entries) = parse_m3u(
+			"#EXTM3U\n#PLAYLIST:Mix\n#EXTINF:123,Artist - Song\na.flac\nb.flac\n#EXTINF:-1 tvg-id=\"x\",Other\n/c.flac\n",
+			&base,
+		);
+		assert_eq!(title.as_deref(), Some("Mix"));
+		assert_eq!(
+			entries,
+			vec![
+				(Ok(PathBuf::from("/music/a.flac")), Some("Artist - Song".to_string())),
+				(Ok(PathBuf::from("/music/b.flac")), None),
+				(Ok(PathBuf::from("/c.flac")), Some("Other".to_string())),
+			]
+		);
+	}
+
+	#[test]
+	fn pls_order() {
+		let base = Url::parse("file:///music/list.pls").unwrap();
+		let (_, entries) = parse_pls(
+			"[playlist]\nFile2=file:///b%20c.flac\nTitle2=B\nfile1=a.flac\nFile3=http://radio/\nNumberOfEntries=3\n",
+			&base,
+		);
+		assert_eq!(
+			entries,
+			vec![
+				(Ok(PathBuf::from("/music/a.flac")), None),
+				(Ok(PathBuf::from("/b c.flac")), None),
+				(Err("http://radio/".to_string()), None),
+			]
+		);
+	}
+
+	#[test]
+	fn format_detection() {
+		let path = Path::new("list.txt");
+		assert_eq!(Format::detect(Path::new("a.M3U8"), ""), Some(Format::M3u));
+		assert_eq!(Format::detect(Path::new("a.pls"), ""), Some(Format::Pls));
+		assert_eq!(Format::detect(path, "\u{feff}#EXTM3U\n"), Some(Format::M3u));
+		assert_eq!(Format::detect(path, "[Playlist]\n"), Some(Format::Pls));
+		assert_eq!(Format::detect(path, "<?xml version=\"1.0\"?>"), Some(Format::Xspf));
+		assert_eq!(Format::detect(path, "a.flac\n"), None);
+	}
+
+	#[test]
+	fn xml_base_attribute() {
+		let content =
+			"<?xml?><playlist version=\"1\" xml:base = 'music/a&amp;b/'><trackList/></playlist>";
+		assert_eq!(xml_base(content).as_deref(), Some("music/a&b/"));
+		assert_eq!(xml_base("<playlist version=\"1\">"), None);
+		// Only the attribute itself, not text mentioning it
+		let content = "<playlist title=\"see xml:base='x'\" xml:base=\"&#x61;&lt;/\"/>";
+		assert_eq!(xml_base(content).as_deref(), Some("a</"));
+		assert_eq!(xml_base("<playlist title=\"see xml:base='x'\">"), None);
+		// Attributes of tracks don't count
+		assert_eq!(xml_base("<playlist><track xml:base=\"x/\"/></playlist>"), None);
+	}
+
+	#[test]
+	fn remapping() {
+		let rules = vec![parse_remap("/old=/new").unwrap()];
+		assert_eq!(remap("/old/a.flac".into(), &rules), PathBuf::from("/new/a.flac"));
+		assert_eq!(remap("/older/a.flac".into(), &rules), PathBuf::from("/older/a.flac"));
+		assert!(parse_remap("=/new").is_err());
+		assert!(parse_remap("/old").is_err());
+	}
+
+	#[test]
+	fn dir_playlists() {
+		let root = Path::new("/music");
+		let tracks: Vec<PathBuf> =
+			["/music/a/x/1.flac", "/music/a/2.flac", "/music/b/y/z/3.flac", "/music/4.flac"]
+				.into_iter()
+				.map(PathBuf::from)
+				.collect();
+		let map = get_dir_playlists(root, &tracks, 2);
+		let mut names: Vec<&str> = map.keys().map(|x| x.as_str()).collect();
+		names.sort();
+		assert_eq!(names, ["a", "a/x", "b", "b/y"]);
+		assert_eq!(map["a"].len(), 2);
+		assert_eq!(*map["b/y"], vec![tracks[2].clone()]);
+	}
+
+	#[test]
+	fn smart_playlists() {
+		let library = vec![
+			TrackInfo {
+				path: "/a.flac".into(),
+				genre: "Jazz".to_string(),
+				..Default::default()
+			},
+			TrackInfo {
+				path: "/b.flac".into(),
+				genre: "Rock".to_string(),
+				..Default::default()
+			},
+		];
+		let queries = HashMap::from([
+			("jazz".to_string(), "genre = jazz".to_string()),
+			("none".to_string(), "genre = pop".to_string()),
+			("broken".to_string(), "genre =".to_string()),
+		]);
+		let map = get_smart_playlists(&library, &queries);
+		assert_eq!(map.len(), 1);
+		assert_eq!(*map["jazz"], vec![PathBuf::from("/a.flac")]);
+	}
+}
@@ -0,0 +1,348 @@
+//! Tag queries for smart playlists
+//!
+//! ```text
+//! genre = jazz AND year < 1970
+//! artist in [Miles Davis, "John Coltrane"] OR NOT (album ~ live)
+//! ```
+//!
+//! Fields: title, artist, album, genre, year, track.
+//! Operators: `=`, `!=`, `<`, `<=`, `>`, `>=`, `~` (contains) and `in [..]`.
+//! Comparison is numeric if both sides are numbers, case-insensitive otherwise.
+
+use std::cmp::Ordering;
+
+use crate::library::TrackInfo;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+	Title,
+	Artist,
+	Album,
+	Genre,
+	Year,
+	Track,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+	Eq,
+	Ne,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+	Contains,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+	And(Box<Query>, Box<Query>),
+	Or(Box<Query>, Box<Query>),
+	Not(Box<Query>),
+	Cmp(Field, Op, String),
+	In(Field, Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+	LParen,
+	RParen,
+	LBracket,
+	RBracket,
+	Comma,
+	Op(Op),
+	Word(String),
+	Str(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = vec![];
+	let mut chars = s.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			c if c.is_whitespace() => {}
+			'(' => tokens.push(Token::LParen),
+			')' => tokens.push(Token::RParen),
+			'[' => tokens.push(Token::LBracket),
+			']' => tokens.push(Token::RBracket),
+			',' => tokens.push(Token::Comma),
+			'~' => tokens.push(Token::Op(Op::Contains)),
+			'=' => {
+				chars.next_if_eq(&'=');
+				tokens.push(Token::Op(Op::Eq))
+			}
+			'!' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::Op(Op::Ne)),
+			'<' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::Op(Op::Le)),
+			'<' => tokens.push(Token::Op(Op::Lt)),
+			'>' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::Op(Op::Ge)),
+			'>' => tokens.push(Token::Op(Op::Gt)),
+			'"' | '\'' => {
+				let mut string = String::new();
+				loop {
+					match chars.next() {
+						Some(x) if x == c => break,
+						Some('\\') => string.extend(chars.next()),
+						Some(x) => string.push(x),
+						None => return Err("unterminated string".to_string()),
+					}
+				}
+				tokens.push(Token::Str(string))
+			}
+			c => {
+				let mut word = String::from(c);
+				while let Some(x) =
+					chars.next_if(|x| !x.is_whitespace() && !"()[],=!<>~\"'".contains(*x))
+				{
+					word.push(x);
+				}
+				tokens.push(Token::Word(word))
+			}
+		}
+	}
+	Ok(tokens)
+}
+
+struct Parser {
+	tokens: Vec<Token>,
+	pos: usize,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos)
+	}
+
+	fn next(&mut self) -> Option<Token> {
+		let token = self.tokens.get(self.pos).cloned();
+		self.pos += 1;
+		token
+	}
+
+	fn keyword(&mut self, keyword: &str) -> bool {
+		match self.peek() {
+			Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword) => {
+				self.pos += 1;
+				true
+			}
+			_ => false,
+		}
+	}
+
+	fn or(&mut self) -> Result<Query, String> {
+		let mut query = self.and()?;
+		while self.keyword("or") {
+			query = Query::Or(Box::new(query), Box::new(self.and()?));
+		}
+		Ok(query)
+	}
+
+	fn and(&mut self) -> Result<Query, String> {
+		let mut query = self.not()?;
+		while self.keyword("and") {
+			query = Query::And(Box::new(query), Box::new(self.not()?));
+		}
+		Ok(query)
+	}
+
+	fn not(&mut self) -> Result<Query, String> {
+		if self.keyword("not") {
+			return Ok(Query::Not(Box::new(self.not()?)));
+		}
+		if self.peek() == Some(&Token::LParen) {
+			self.pos += 1;
+			let query = self.or()?;
+			return match self.next() {
+				Some(Token::RParen) => Ok(query),
+				_ => Err("expected ')'".to_string()),
+			};
+		}
+		self.condition()
+	}
+
+	fn condition(&mut self) -> Result<Query, String> {
+		let field = match self.next() {
+			Some(Token::Word(w)) => match w.to_lowercase().as_str() {
+				"title" => Field::Title,
+				"artist" => Field::Artist,
+				"album" => Field::Album,
+				"genre" => Field::Genre,
+				"year" => Field::Year,
+				"track" => Field::Track,
+				_ => return Err(format!("unknown field \"{}\"", w)),
+			},
+			t => return Err(format!("expected field, got {:?}", t)),
+		};
+		if self.keyword("in") {
+			if self.next() != Some(Token::LBracket) {
+				return Err("expected '[' after \"in\"".to_string());
+			}
+			let mut values = vec![];
+			loop {
+				values.push(self.value()?);
+				match self.next() {
+					Some(Token::Comma) => continue,
+					Some(Token::RBracket) => break,
+					_ => return Err("expected ',' or ']'".to_string()),
+				}
+			}
+			return Ok(Query::In(field, values));
+		}
+		match self.next() {
+			Some(Token::Op(op)) => Ok(Query::Cmp(field, op, self.value()?)),
+			t => Err(format!("expected operator, got {:?}", t)),
+		}
+	}
+
+	/// Quoted string or bare words up to the next keyword or punctuation
+	fn value(&mut self) -> Result<String, String> {
+		if let Some(Token::Str(_)) = self.peek() {
+			if let Some(Token::Str(s)) = self.next() {
+				return Ok(s);
+			}
+		}
+		let mut words: Vec<String> = vec![];
+		while let Some(Token::Word(w)) = self.peek() {
+			if w.eq_ignore_ascii_case("and") || w.eq_ignore_ascii_case("or") {
+				break;
+			}
+			words.push(w.clone());
+			self.pos += 1;
+		}
+		if words.is_empty() {
+			return Err("expected value".to_string());
+		}
+		Ok(words.join(" "))
+	}
+}
+
+impl Query {
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let mut parser = Parser {
+			tokens: tokenize(s)?,
+			pos: 0,
+		};
+		let query = parser.or()?;
+		match parser.peek() {
+			None => Ok(query),
+			Some(t) => Err(format!("unexpected {:?}", t)),
+		}
+	}
+
+	pub fn matches(&self, track: &TrackInfo) -> bool {
+		match self {
+			Query::And(a, b) => a.matches(track) && b.matches(track),
+			Query::Or(a, b) => a.matches(track) || b.matches(track),
+			Query::Not(q) => !q.matches(track),
+			Query::In(field, values) => match get(track, *field) {
+				Some(x) => values.iter().any(|v| compare(&x, v) == Ordering::Equal),
+				None => false,
+			},
+			Query::Cmp(field, op, value) => {
+				let Some(x) = get(track, *field) else {
+					return *op == Op::Ne;
+				};
+				match op {
+					Op::Eq => compare(&x, value) == Ordering::Equal,
+					Op::Ne => compare(&x, value) != Ordering::Equal,
+					Op::Lt => compare(&x, value) == Ordering::Less,
+					Op::Le => compare(&x, value) != Ordering::Greater,
+					Op::Gt => compare(&x, value) == Ordering::Greater,
+					Op::Ge => compare(&x, value) != Ordering::Less,
+					Op::Contains => x.to_lowercase().contains(&value.to_lowercase()),
+				}
+			}
+		}
+	}
+}
+
+fn get(track: &TrackInfo, field: Field) -> Option<String> {
+	let value = match field {
+		Field::Title => track.title.clone(),
+		Field::Artist => track.artist.clone(),
+		Field::Album => track.album.clone(),
+		Field::Genre => track.genre.clone(),
+		Field::Year => return track.year.map(|x| x.to_string()),
+		Field::Track => return track.track_number.map(|x| x.to_string()),
+	};
+	Some(value).filter(|x| !x.is_empty())
+}
+
+fn compare(a: &str, b: &str) -> Ordering {
+	match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+		(Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+		_ => a.to_lowercase().cmp(&b.to_lowercase()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn track(artist: &str, genre: &str, year: Option<u32>) -> TrackInfo {
+		TrackInfo {
+			artist: artist.to_string(),
+			genre: genre.to_string(),
+			year,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn precedence() {
+		let query = Query::parse("genre = jazz AND year < 1970 OR NOT artist ~ miles").unwrap();
+		assert_eq!(
+			query,
+			Query::Or(
+				Box::new(Query::And(
+					Box::new(Query::Cmp(Field::Genre, Op::Eq, "jazz".to_string())),
+					Box::new(Query::Cmp(Field::Year, Op::Lt, "1970".to_string())),
+				)),
+				Box::new(Query::Not(Box::new(Query::Cmp(
+					Field::Artist,
+					Op::Contains,
+					"miles".to_string()
+				)))),
+			)
+		);
+	}
+
+	#[test]
+	fn values() {
+		let query = Query::parse("artist in [Miles Davis, \"John \\\"Trane\\\"\"]").unwrap();
+		assert_eq!(
+			query,
+			Query::In(Field::Artist, vec!["Miles Davis".to_string(), "John \"Trane\"".to_string()])
+		);
+	}
+
+	#[test]
+	fn matching() {
+		let query = Query::parse("(genre = JAZZ and year <= 1959) or artist != x").unwrap();
+		assert!(query.matches(&track("x", "Jazz", Some(1959))));
+		assert!(!query.matches(&track("x", "Jazz", Some(1960))));
+		assert!(!query.matches(&track("x", "Jazz", None)));
+		// Missing fields are not equal to anything
+		assert!(query.matches(&track("", "Rock", None)));
+		// Numbers are compared as numbers
+		assert!(Query::parse("year > 999").unwrap().matches(&track("", "", Some(1000))));
+	}
+
+	#[test]
+	fn errors() {
+		for (query, error) in [
+			("", "expected field, got None"),
+			("colour = red", "unknown field \"colour\""),
+			("genre jazz", "expected operator, got Some(Word(\"jazz\"))"),
+			("genre =", "expected value"),
+			("genre = \"jazz", "unterminated string"),
+			("(genre = jazz", "expected ')'"),
+			("genre = jazz)", "unexpected RParen"),
+			("genre in jazz", "expected '[' after \"in\""),
+			("genre in [jazz", "expected ',' or ']'"),
+			("genre in [jazz,]", "expected value"),
+			("genre = jazz and", "expected field, got None"),
+		] {
+			assert_eq!(Query::parse(query), Err(error.to_string()), "{}", query);
+		}
+	}
+}
@@ -0,0 +1,135 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+static SHUTDOWN: Shutdown = Shutdown::new();
+
+struct Shutdown {
+	/// Set on SIGTERM or SIGINT, streams are cut off after it
+	deadline: OnceLock<Instant>,
+	/// Streams still running
+	streams: AtomicUsize,
+}
+
+impl Shutdown {
+	const fn new() -> Self {
+		Self {
+			deadline: OnceLock::new(),
+			streams: AtomicUsize::new(0),
+		}
+	}
+
+	fn begin(&self, deadline: Duration) {
+		let _ = self.deadline.set(Instant::now() + deadline);
+	}
+
+	fn stopping(&self) -> bool {
+		self.deadline.get().is_some()
+	}
+
+	fn expired(&self) -> bool {
+		self.deadline.get().is_some_and(|x| Instant::now() >= *x)
+	}
+
+	async fn drained(&self) {
+		while self.streams.load(Ordering::SeqCst) > 0 {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+	}
+}
+
+/// Waits for SIGTERM or SIGINT
+pub async fn signal() {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{signal, SignalKind};
+		let mut terminate = signal(SignalKind::terminate()).unwrap();
+		tokio::select! {
+			_ = terminate.recv() => {}
+			_ = tokio::signal::ctrl_c() => {}
+		}
+	}
+	#[cfg(not(unix))]
+	let _ = tokio::signal::ctrl_c().await;
+}
+
+/// No new tracks are started after this, current ones are cut off after
+/// `deadline`
+pub fn begin(deadline: Duration) {
+	SHUTDOWN.begin(deadline)
+}
+
+pub fn stopping() -> bool {
+	SHUTDOWN.stopping()
+}
+
+pub fn expired() -> bool {
+	SHUTDOWN.expired()
+}
+
+/// Waits until every stream has ended
+pub async fn drained() {
+	SHUTDOWN.drained().await
+}
+
+/// Counts the stream as running while alive
+pub struct Running(&'static Shutdown);
+
+impl Running {
+	pub fn new() -> Self {
+		Self::of(&SHUTDOWN)
+	}
+
+	fn of(shutdown: &'static Shutdown) -> Self {
+		shutdown.streams.fetch_add(1, Ordering::SeqCst);
+		Self(shutdown)
+	}
+}
+
+impl Drop for Running {
+	fn drop(&mut self) {
+		self.0.streams.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn shutdown() -> &'static Shutdown {
+		Box::leak(Box::new(Shutdown::new()))
+	}
+
+	#[test]
+	fn deadline() {
+		let shutdown = shutdown();
+		assert!(!shutdown.stopping() && !shutdown.expired());
+		shutdown.begin(Duration::from_secs(3600));
+		assert!(shutdown.stopping() && !shutdown.expired());
+		// Second signal doesn't move the deadline
+		shutdown.begin(Duration::ZERO);
+		assert!(!shutdown.expired());
+
+		let shutdown = self::shutdown();
+		shutdown.begin(Duration::ZERO);
+		assert!(shutdown.stopping() && shutdown.expired());
+	}
+
+	#[tokio::test]
+	async fn drained() {
+		let shutdown = shutdown();
+		let wait = Duration::from_millis(300);
+		assert!(tokio::time::timeout(wait, shutdown.drained()).await.is_ok());
+
+		let (first, second) = (Running::of(shutdown), Running::of(shutdown));
+		assert_eq!(shutdown.streams.load(Ordering::SeqCst), 2);
+		drop(first);
+		assert!(tokio::time::timeout(wait, shutdown.drained()).await.is_err());
+		tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			drop(second);
+		});
+		assert!(tokio::time::timeout(wait * 2, shutdown.drained()).await.is_ok());
+		assert_eq!(shutdown.streams.load(Ordering::SeqCst), 0);
+	}
+}
@@ -0,0 +1,102 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rand::prelude::*;
+
+/// Tracks to be streamed: explicitly selected ones in order, then random
+/// tracks from the station's tracklist
+pub struct TrackSource {
+	queue: VecDeque<PathBuf>,
+	shuffle: Arc<Vec<PathBuf>>,
+}
+
+impl TrackSource {
+	pub fn shuffle(tracklist: Arc<Vec<PathBuf>>) -> Self {
+		Self {
+			queue: VecDeque::new(),
+			shuffle: tracklist,
+		}
+	}
+
+	/// Plays `queue` first and falls back to shuffling `then`
+	pub fn queue(queue: Vec<PathBuf>, then: Arc<Vec<PathBuf>>) -> Self {
+		Self {
+			queue: queue.into(),
+			shuffle: then,
+		}
+	}
+
+	pub fn next(&mut self) -> Option<PathBuf> {
+		match self.queue.pop_front() {
+			Some(track) => Some(track),
+			None => self.shuffle.choose(&mut thread_rng()).cloned(),
+		}
+	}
+}
+
+/// Everything that could be played
+pub struct Catalog {
+	/// The whole library
+	pub tracklist: Arc<Vec<PathBuf>>,
+	pub playlists: HashMap<String, Arc<Vec<PathBuf>>>,
+}
+
+impl Catalog {
+	/// Playlist by name, "" is the whole library
+	pub fn playlist(&self, name: &str) -> Option<Arc<Vec<PathBuf>>> {
+		if name.is_empty() {
+			Some(self.tracklist.clone())
+		} else {
+			self.playlists.get(name).cloned()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn paths(paths: &[&str]) -> Vec<PathBuf> {
+		paths.iter().map(PathBuf::from).collect()
+	}
+
+	#[test]
+	fn queue_then_shuffle() {
+		let then = Arc::new(paths(&["/x", "/y"]));
+		let mut source = TrackSource::queue(paths(&["/b", "/a", "/b"]), then.clone());
+		for track in ["/b", "/a", "/b"] {
+			assert_eq!(source.next(), Some(PathBuf::from(track)));
+		}
+		for _ in 0..10 {
+			assert!(then.contains(&source.next().unwrap()));
+		}
+	}
+
+	#[test]
+	fn shuffle() {
+		let tracklist = Arc::new(paths(&["/x"]));
+		let mut source = TrackSource::shuffle(tracklist);
+		assert_eq!(source.next(), Some(PathBuf::from("/x")));
+		assert_eq!(source.next(), Some(PathBuf::from("/x")));
+	}
+
+	#[test]
+	fn nothing_to_fall_back_to() {
+		let mut source = TrackSource::queue(paths(&["/a"]), Arc::new(vec![]));
+		assert_eq!(source.next(), Some(PathBuf::from("/a")));
+		assert_eq!(source.next(), None);
+		assert_eq!(TrackSource::shuffle(Arc::new(vec![])).next(), None);
+	}
+
+	#[test]
+	fn catalog_playlists() {
+		let catalog = Catalog {
+			tracklist: Arc::new(paths(&["/a", "/b"])),
+			playlists: HashMap::from([("jazz".to_string(), Arc::new(paths(&["/a"])))]),
+		};
+		assert_eq!(*catalog.playlist("").unwrap(), paths(&["/a", "/b"]));
+		assert_eq!(*catalog.playlist("jazz").unwrap(), paths(&["/a"]));
+		assert_eq!(catalog.playlist("rock"), None);
+	}
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lonelyradio_types::{RecentTrack, StatusResponce};
+use once_cell::sync::Lazy;
+
+use crate::auth::Access;
+
+/// Recently started tracks kept for Request::Status
+const RECENT: usize = 20;
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+static PLAYED: Lazy<Mutex<VecDeque<(Instant, RecentTrack)>>> = Lazy::new(Default::default);
+
+/// Uptime is counted from here
+pub fn init() {
+	Lazy::force(&STARTED);
+}
+
+pub fn played(station: &str, id: u64, artist: &str, album: &str, title: &str) {
+	remember(
+		&mut PLAYED.lock().unwrap(),
+		RecentTrack {
+			station: station.to_string(),
+			id,
+			title: title.to_string(),
+			album: album.to_string(),
+			artist: artist.to_string(),
+			ago: 0,
+		},
+	);
+}
+
+/// Adds the track as the newest, keeping `RECENT` tracks
+fn remember(played: &mut VecDeque<(Instant, RecentTrack)>, track: RecentTrack) {
+	if played.len() == RECENT {
+		played.pop_back();
+	}
+	played.push_front((Instant::now(), track));
+}
+
+/// Listeners and recent tracks of stations available with `access`
+pub fn status(access: &Access, listeners: BTreeMap<String, u64>) -> StatusResponce {
+	visible(|x| access.allows(x), STARTED.elapsed(), listeners, &PLAYED.lock().unwrap())
+}
+
+/// Status showing only the stations `allows` lets through
+fn visible(
+	allows: impl Fn(&str) -> bool,
+	uptime: Duration,
+	listeners: BTreeMap<String, u64>,
+	played: &VecDeque<(Instant, RecentTrack)>,
+) -> StatusResponce {
+	StatusResponce {
+		uptime: uptime.as_secs(),
+		listeners: listeners.into_iter().filter(|(station, _)| allows(station)).collect(),
+		recent: played
+			.iter()
+			.filter(|(_, track)| allows(&track.station))
+			.map(|(started, track)| RecentTrack {
+				ago: started.elapsed().as_secs(),
+				..track.clone()
+			})
+			.collect(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde::de::IgnoredAny;
+
+	use super::*;
+
+	fn track(station: &str, id: u64) -> RecentTrack {
+		RecentTrack {
+			station: station.to_string(),
+			id,
+			title: format!("Track {}", id),
+			album: "Album".to_string(),
+			artist: "Artist".to_string(),
+			ago: 0,
+		}
+	}
+
+	fn listeners() -> BTreeMap<String, u64> {
+		[("", 3), ("jazz", 1), ("private", 2)].map(|(k, v)| (k.to_string(), v)).into()
+	}
+
+	#[test]
+	fn recent_tracks() {
+		let mut played = VecDeque::new();
+		for id in 0..RECENT as u64 + 5 {
+			remember(&mut played, track("", id));
+		}
+		let status = visible(|_| true, Duration::from_secs(90), BTreeMap::new(), &played);
+		assert_eq!(status.uptime, 90);
+		let ids: Vec<u64> = status.recent.iter().map(|x| x.id).collect();
+		// Newest first, the oldest are forgotten
+		assert_eq!(ids, (5..RECENT as u64 + 5).rev().collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn stations_filtered() {
+		let mut played = VecDeque::new();
+		remember(&mut played, track("jazz", 1));
+		remember(&mut played, track("private", 2));
+		remember(&mut played, track("", 3));
+		let status = visible(|x| x != "private", Duration::ZERO, listeners(), &played);
+		assert_eq!(
+			status.listeners,
+			[("", 3), ("jazz", 1)].map(|(k, v)| (k.to_string(), v)).into()
+		);
+		assert_eq!(status.recent.iter().map(|x| x.id).collect::<Vec<_>>(), [3, 1]);
+
+		let status = visible(|x| x.is_empty(), Duration::ZERO, listeners(), &played);
+		assert_eq!(status.listeners.keys().collect::<Vec<_>>(), [""]);
+		assert_eq!(status.recent.len(), 1);
+		assert!(visible(|_| false, Duration::ZERO, listeners(), &played).recent.is_empty());
+	}
+
+	#[test]
+	fn serialized() {
+		let mut played = VecDeque::new();
+		played.push_front((Instant::now() - Duration::from_secs(65), track("jazz", 7)));
+		let status = visible(|_| true, Duration::from_secs(3600), listeners(), &played);
+		assert_eq!(status.recent[0].ago, 65);
+
+		let bytes = rmp_serde::to_vec_named(&status).unwrap();
+		assert_eq!(rmp_serde::from_slice::<StatusResponce>(&bytes).unwrap(), status);
+		// Short field names, same as everything else on the wire
+		#[derive(serde::Deserialize)]
+		struct Fields {
+			r: Vec<BTreeMap<String, IgnoredAny>>,
+		}
+		let fields: BTreeMap<String, IgnoredAny> = rmp_serde::from_slice(&bytes).unwrap();
+		assert_eq!(fields.keys().collect::<Vec<_>>(), ["l", "r", "u"]);
+		let fields: Fields = rmp_serde::from_slice(&bytes).unwrap();
+		assert_eq!(fields.r[0].keys().collect::<Vec<_>>(), ["a", "id", "mal", "mar", "mt", "st"]);
+	}
+}
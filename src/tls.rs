@@ -0,0 +1,141 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::listen::Socket;
+
+/// TLS settings for the listener from certificate chain and private key (PEM)
+pub fn server_config(cert: &Path, key: &Path) -> Arc<ServerConfig> {
+	let chain = CertificateDer::pem_file_iter(cert)
+		.and_then(|x| x.collect::<Result<Vec<_>, _>>())
+		.unwrap_or_else(|e| panic!("failed to read certificate {:?}: {}", cert, e));
+	let Some(leaf) = chain.first() else {
+		panic!("no certificates in {:?}", cert)
+	};
+	// Clients pin this one for self-signed certificates
+	info!("TLS certificate SHA-256: {}", fingerprint(leaf));
+	let key = PrivateKeyDer::from_pem_file(key)
+		.unwrap_or_else(|e| panic!("failed to read private key {:?}: {}", key, e));
+	Arc::new(
+		ServerConfig::builder()
+			.with_no_client_auth()
+			.with_single_cert(chain, key)
+			.unwrap_or_else(|e| panic!("invalid certificate or key: {}", e)),
+	)
+}
+
+/// Completes the handshake, None if it failed or the client was too slow (see
+/// Transport::set_timeout())
+pub fn accept(
+	config: &Arc<ServerConfig>,
+	mut s: Socket,
+) -> Option<StreamOwned<ServerConnection, Socket>> {
+	let mut connection = ServerConnection::new(config.clone()).ok()?;
+	while connection.is_handshaking() {
+		match connection.complete_io(&mut s) {
+			// Closed by the client
+			Ok((0, 0)) | Err(_) => return None,
+			Ok(_) => {}
+		}
+	}
+	Some(StreamOwned::new(connection, s))
+}
+
+fn fingerprint(cert: &CertificateDer) -> String {
+	Sha256::digest(cert).iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(":")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::path::PathBuf;
+	use std::time::{Duration, Instant};
+
+	use rustls::pki_types::ServerName;
+	use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+	use super::*;
+	use crate::transport::Transport;
+
+	/// Self-signed certificate for localhost, written to PEM files
+	fn certificate(name: &str) -> (CertificateDer<'static>, PathBuf, PathBuf) {
+		let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+		let dir = std::env::temp_dir();
+		let cert = dir.join(format!("lonelyradio-{}-{}.crt", name, std::process::id()));
+		let key = dir.join(format!("lonelyradio-{}-{}.key", name, std::process::id()));
+		std::fs::write(&cert, certified.cert.pem()).unwrap();
+		std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+		(certified.cert.der().clone(), cert, key)
+	}
+
+	fn server(name: &str) -> (CertificateDer<'static>, Arc<ServerConfig>, TcpListener) {
+		let (der, cert, key) = certificate(name);
+		let config = server_config(&cert, &key);
+		let _ = std::fs::remove_file(cert);
+		let _ = std::fs::remove_file(key);
+		(der, config, TcpListener::bind("127.0.0.1:0").unwrap())
+	}
+
+	fn accept_one(
+		config: Arc<ServerConfig>,
+		listener: &TcpListener,
+		timeout: Duration,
+	) -> Option<StreamOwned<ServerConnection, Socket>> {
+		let s = Socket::Tcp(listener.accept().unwrap().0);
+		s.set_timeout(Some(timeout)).unwrap();
+		accept(&config, s)
+	}
+
+	#[test]
+	fn handshake_and_echo() {
+		let (der, config, listener) = server("echo");
+		let address = listener.local_addr().unwrap();
+		let server = std::thread::spawn(move || {
+			let mut s = accept_one(config, &listener, Duration::from_secs(5)).unwrap();
+			let mut hello = [0u8; 8];
+			s.read_exact(&mut hello).unwrap();
+			s.write_all(&hello).unwrap();
+			s.flush().unwrap();
+		});
+
+		let mut roots = RootCertStore::empty();
+		roots.add(der).unwrap();
+		let client_config =
+			ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+		let connection = ClientConnection::new(
+			Arc::new(client_config),
+			ServerName::try_from("localhost").unwrap(),
+		)
+		.unwrap();
+		let mut client = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+		client.write_all(b"lonelyrv").unwrap();
+		let mut echo = [0u8; 8];
+		client.read_exact(&mut echo).unwrap();
+		assert_eq!(&echo, b"lonelyrv");
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn silent_client_times_out() {
+		let (_, config, listener) = server("silent");
+		let address = listener.local_addr().unwrap();
+		let _client = TcpStream::connect(address).unwrap();
+		let start = Instant::now();
+		assert!(accept_one(config, &listener, Duration::from_millis(200)).is_none());
+		assert!(start.elapsed() < Duration::from_secs(5));
+	}
+
+	#[test]
+	fn closed_client_is_rejected() {
+		let (_, config, listener) = server("closed");
+		let address = listener.local_addr().unwrap();
+		drop(TcpStream::connect(address).unwrap());
+		assert!(accept_one(config, &listener, Duration::from_secs(5)).is_none());
+	}
+}
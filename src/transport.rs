@@ -0,0 +1,188 @@
+use std::io::{Cursor, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+use rustls::{ServerConnection, StreamOwned};
+use tungstenite::Message;
+
+use crate::listen::Socket;
+
+/// Connection to a client
+pub trait Transport: Read + Write + Send + 'static {
+	/// Whether there is incoming data (or the connection was closed), without
+	/// blocking
+	fn readable(&mut self) -> bool;
+
+	/// For clients that can't apply backpressure: audio is sent in real time,
+	/// at most this far ahead
+	fn lead(&self) -> Option<Duration> {
+		None
+	}
+
+	/// Address of the client, if it has one
+	fn peer(&self) -> Option<IpAddr> {
+		None
+	}
+
+	/// Limits every read and write, None blocks indefinitely
+	fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+}
+
+impl Transport for TcpStream {
+	fn readable(&mut self) -> bool {
+		if self.set_nonblocking(true).is_err() {
+			return true;
+		}
+		let readable =
+			!matches!(self.peek(&mut [0u8]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+		self.set_nonblocking(false).is_err() || readable
+	}
+
+	// IPv4 clients of IPv6 listeners are reported as plain IPv4
+	fn peer(&self) -> Option<IpAddr> {
+		self.peer_addr().ok().map(|x| x.ip().to_canonical())
+	}
+
+	fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.set_read_timeout(timeout)?;
+		self.set_write_timeout(timeout)
+	}
+}
+
+impl Transport for StreamOwned<ServerConnection, Socket> {
+	// Only complete records count, as reading a partial one would block
+	fn readable(&mut self) -> bool {
+		loop {
+			match self.conn.process_new_packets() {
+				Ok(state) if state.plaintext_bytes_to_read() > 0 || state.peer_has_closed() => {
+					return true
+				}
+				Ok(_) => {}
+				Err(_) => return true,
+			}
+			if !self.sock.readable() {
+				return false;
+			}
+			if self.sock.set_nonblocking(true).is_err() {
+				return true;
+			}
+			let read = self.conn.read_tls(&mut self.sock);
+			if self.sock.set_nonblocking(false).is_err() {
+				return true;
+			}
+			match read {
+				Ok(0) => return true,
+				Ok(_) => {}
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return false,
+				Err(_) => return true,
+			}
+		}
+	}
+
+	fn peer(&self) -> Option<IpAddr> {
+		self.sock.peer()
+	}
+
+	fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.sock.set_timeout(timeout)
+	}
+}
+
+/// WebSocket connection, every write is sent as one binary message (the server
+/// writes every protocol message at once)
+pub struct WebSocket {
+	socket: tungstenite::WebSocket<TcpStream>,
+	/// Unread part of the last received message
+	incoming: Cursor<Vec<u8>>,
+}
+
+impl WebSocket {
+	pub fn new(socket: tungstenite::WebSocket<TcpStream>) -> Self {
+		Self {
+			socket,
+			incoming: Cursor::new(vec![]),
+		}
+	}
+
+	fn buffered(&self) -> bool {
+		self.incoming.position() < self.incoming.get_ref().len() as u64
+	}
+
+	/// Waits for the next binary message, false if the connection was closed
+	fn receive(&mut self) -> std::io::Result<bool> {
+		loop {
+			match self.socket.read() {
+				Ok(Message::Binary(data)) => {
+					self.incoming = Cursor::new(data.to_vec());
+					return Ok(true);
+				}
+				Ok(Message::Close(_))
+				| Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+					return Ok(false)
+				}
+				// Pings are answered by tungstenite
+				Ok(_) => {}
+				Err(tungstenite::Error::Io(e)) => return Err(e),
+				Err(e) => return Err(std::io::Error::other(e)),
+			}
+		}
+	}
+}
+
+impl Read for WebSocket {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if !self.buffered() && !self.receive()? {
+			return Ok(0);
+		}
+		self.incoming.read(buf)
+	}
+}
+
+impl Write for WebSocket {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self.socket.send(Message::binary(buf.to_vec())) {
+			Ok(()) => Ok(buf.len()),
+			Err(tungstenite::Error::Io(e)) => Err(e),
+			Err(e) => Err(std::io::Error::other(e)),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self.socket.flush() {
+			Ok(()) => Ok(()),
+			Err(tungstenite::Error::Io(e)) => Err(e),
+			Err(e) => Err(std::io::Error::other(e)),
+		}
+	}
+}
+
+impl Transport for WebSocket {
+	// Messages could already be buffered by tungstenite, so it's asked instead
+	// of the socket
+	fn readable(&mut self) -> bool {
+		if self.buffered() {
+			return true;
+		}
+		if self.socket.get_mut().set_nonblocking(true).is_err() {
+			return true;
+		}
+		let received = self.receive();
+		if self.socket.get_mut().set_nonblocking(false).is_err() {
+			return true;
+		}
+		!matches!(received, Err(e) if e.kind() == ErrorKind::WouldBlock)
+	}
+
+	// Browsers receive messages as fast as they arrive
+	fn lead(&self) -> Option<Duration> {
+		Some(Duration::from_secs(10))
+	}
+
+	fn peer(&self) -> Option<IpAddr> {
+		self.socket.get_ref().peer()
+	}
+
+	fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+		self.socket.get_ref().set_timeout(timeout)
+	}
+}
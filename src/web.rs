@@ -0,0 +1,114 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::listen::HANDSHAKE_TIMEOUT;
+use crate::transport::{Transport, WebSocket};
+
+/// Browser player, served to plain HTTP requests on the WebSocket listener
+const PLAYER: &str = include_str!("player.html");
+
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peeks HTTP request head (up to 4 KiB) without consuming it, returns it with
+/// its length in bytes. Blocks for up to HEAD_TIMEOUT and leaves it as the read
+/// timeout of the stream
+pub fn peek_head(stream: &TcpStream) -> Option<(String, usize)> {
+	stream.set_read_timeout(Some(HEAD_TIMEOUT)).ok()?;
+	let mut head = [0u8; 4096];
+	let start = Instant::now();
+	loop {
+		let n = stream.peek(&mut head).ok()?;
+		if n == 0 || start.elapsed() > HEAD_TIMEOUT {
+			return None;
+		}
+		if n == head.len() || head[..n].windows(4).any(|x| x == b"\r\n\r\n") {
+			return Some((String::from_utf8_lossy(&head[..n]).to_string(), n));
+		}
+		std::thread::sleep(Duration::from_millis(10));
+	}
+}
+
+/// Accepts a WebSocket connection or serves the player to anything else. The
+/// connection is left with HANDSHAKE_TIMEOUT (see Transport::set_timeout())
+pub fn accept(mut stream: TcpStream) -> Option<WebSocket> {
+	stream.set_write_timeout(Some(HEAD_TIMEOUT)).ok()?;
+	// Request head is peeked, tungstenite reads it again on upgrade
+	let (head, length) = peek_head(&stream)?;
+	let request = head.to_ascii_lowercase();
+	if request.contains("upgrade: websocket") {
+		let socket = WebSocket::new(tungstenite::accept(stream).ok()?);
+		socket.set_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+		return Some(socket);
+	}
+
+	stream.read_exact(&mut vec![0u8; length]).ok()?;
+	let response = match request.split_whitespace().nth(1) {
+		Some("/" | "/index.html") => format!(
+			"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: \
+			 {}\r\nConnection: close\r\n\r\n{}",
+			PLAYER.len(),
+			PLAYER
+		),
+		_ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+	};
+	let _ = stream.write_all(response.as_bytes());
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+
+	use tungstenite::Message;
+
+	use super::*;
+
+	fn server<T: Send + 'static>(
+		f: impl FnOnce(Option<WebSocket>) -> T + Send + 'static,
+	) -> (String, std::thread::JoinHandle<T>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		let server = std::thread::spawn(move || f(accept(listener.accept().unwrap().0)));
+		(address, server)
+	}
+
+	#[test]
+	fn websocket_echo() {
+		let (address, server) = server(|s| {
+			let mut s = s.unwrap();
+			let mut hello = [0u8; 8];
+			s.read_exact(&mut hello).unwrap();
+			s.write_all(&hello).unwrap();
+		});
+		let (mut client, _) = tungstenite::client(
+			format!("ws://{}/", address),
+			TcpStream::connect(&address).unwrap(),
+		)
+		.unwrap();
+		client.send(Message::binary(b"lonelyrv".to_vec())).unwrap();
+		assert_eq!(client.read().unwrap(), Message::binary(b"lonelyrv".to_vec()));
+		server.join().unwrap();
+	}
+
+	#[test]
+	fn player_is_served() {
+		let (address, server) = server(|s| s.is_none());
+		let mut client = TcpStream::connect(&address).unwrap();
+		client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+		let mut response = String::new();
+		client.read_to_string(&mut response).unwrap();
+		assert!(response.starts_with("HTTP/1.1 200 OK"));
+		assert!(response.ends_with(PLAYER));
+		assert!(server.join().unwrap());
+	}
+
+	#[test]
+	fn silent_client_times_out() {
+		let (address, server) = server(|s| s.is_none());
+		let _client = TcpStream::connect(&address).unwrap();
+		let start = Instant::now();
+		assert!(server.join().unwrap());
+		assert!(start.elapsed() < HEAD_TIMEOUT * 2);
+	}
+}